@@ -0,0 +1,174 @@
+use crate::error::Result;
+use crate::export::{self, Format};
+use crate::output;
+use crate::query;
+use colored::*;
+use futures_util::stream::{self, StreamExt, TryStreamExt};
+use mongodb::bson::{doc, Bson, Document};
+use mongodb::options::{AggregateOptions, FindOptions};
+use mongodb::{Collection, Cursor};
+use serde_json::Value;
+use std::fs;
+
+/// Documents printed per page when no `--limit` is given.
+pub const PAGE_SIZE: i64 = 20;
+
+/// An ad-hoc query from the command line, with `limit` documents per `page` (1-based). Runs
+/// `pipeline` when one was loaded, otherwise a `find` with `filter`, `projection` and `sort`.
+#[derive(Debug, Default)]
+pub struct AdhocQuery {
+    pub filter: Document,
+    pub projection: Option<Document>,
+    pub sort: Option<Document>,
+    pub pipeline: Option<Vec<Document>>,
+    pub limit: Option<i64>,
+    pub page: u64,
+}
+
+impl AdhocQuery {
+    /// Parses the extended JSON `--filter`, `--projection` and `--sort`, or the pipeline file
+    /// given with `--agg`; the two forms cannot be combined.
+    pub fn new(
+        filter: Option<&str>,
+        projection: Option<&str>,
+        sort: Option<&str>,
+        agg: Option<&str>,
+        limit: Option<i64>,
+        page: u64,
+    ) -> Result<Self> {
+        if limit.is_some_and(|l| l <= 0) {
+            return Err("--limit must be greater than zero".into());
+        }
+        if page == 0 {
+            return Err("--page starts at 1".into());
+        }
+        if page > 1 && limit.is_none() {
+            return Err("--page needs --limit when writing to a file".into());
+        }
+
+        match agg {
+            Some(path) => {
+                if filter.is_some() || projection.is_some() || sort.is_some() {
+                    return Err("--agg cannot be combined with --filter, --projection or --sort".into());
+                }
+                let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+                let stages: Vec<Value> = serde_json::from_str(&text)
+                    .map_err(|e| format!("{}: expected a JSON array of stages: {}", path, e))?;
+                let mut pipeline = Vec::new();
+                for stage in stages {
+                    match Bson::try_from(stage)? {
+                        Bson::Document(stage) => pipeline.push(stage),
+                        other => return Err(format!("{}: pipeline stage is not a document: {}", path, other).into()),
+                    }
+                }
+                query::read_only(&pipeline).map_err(|e| format!("{}: {}", path, e))?;
+                Ok(AdhocQuery {
+                    pipeline: Some(pipeline),
+                    limit,
+                    page,
+                    ..Default::default()
+                })
+            }
+            None => Ok(AdhocQuery {
+                filter: filter.map(|f| parse("--filter", f)).transpose()?.unwrap_or_default(),
+                projection: projection.map(|p| parse("--projection", p)).transpose()?,
+                sort: sort.map(|s| parse("--sort", s)).transpose()?,
+                pipeline: None,
+                limit,
+                page,
+            }),
+        }
+    }
+
+    fn skip(&self) -> u64 {
+        self.limit.map_or(0, |limit| (self.page - 1) * limit as u64)
+    }
+
+    async fn cursor(&self, collection: &Collection<Document>) -> Result<Cursor<Document>> {
+        Ok(match &self.pipeline {
+            None => {
+                let options = FindOptions::builder()
+                    .projection(self.projection.clone())
+                    .sort(self.sort.clone())
+                    .skip(self.skip())
+                    .limit(self.limit)
+                    .build();
+                query::find(collection, self.filter.clone(), options).await?
+            }
+            Some(pipeline) => {
+                let mut pipeline = pipeline.clone();
+                if let Some(limit) = self.limit {
+                    if self.skip() > 0 {
+                        pipeline.push(doc! { "$skip": self.skip() as i64 });
+                    }
+                    pipeline.push(doc! { "$limit": limit });
+                }
+                let options = AggregateOptions::builder().allow_disk_use(true).build();
+                query::aggregate(collection, pipeline, options).await?
+            }
+        })
+    }
+
+    /// Included fields of the projection, which become the export columns; dotted paths are
+    /// looked up in the nested documents.
+    fn projected_columns(&self) -> Option<Vec<String>> {
+        let projection = self.projection.as_ref().filter(|_| self.pipeline.is_none())?;
+        let columns: Vec<String> = projection
+            .iter()
+            .filter(|(_, v)| !matches!(v, Bson::Int32(0) | Bson::Int64(0) | Bson::Boolean(false)))
+            .map(|(k, _)| k.clone())
+            .collect();
+        (!columns.is_empty()).then_some(columns)
+    }
+}
+
+fn parse(flag: &str, json: &str) -> Result<Document> {
+    let value: Value = serde_json::from_str(json).map_err(|e| format!("{} is not valid JSON: {}", flag, e))?;
+    match Bson::try_from(value)? {
+        Bson::Document(doc) => Ok(doc),
+        other => Err(format!("{} must be a JSON object, got {}", flag, other).into()),
+    }
+}
+
+/// Prints one page of results.
+pub async fn run(collection: &Collection<Document>, query: &AdhocQuery) -> Result<()> {
+    let mut cursor = query.cursor(collection).await?;
+    let mut printer = output::Printer::new(query.projected_columns().unwrap_or_default());
+    while let Some(doc) = cursor.try_next().await? {
+        printer.record(&doc)?;
+    }
+    let printed = printer.count();
+    printer.finish()?;
+    if output::format() != output::Format::Table {
+        return Ok(());
+    }
+
+    let footer = format!("page {}: {} documents", query.page, printed);
+    match query.limit {
+        Some(limit) if printed == limit as u64 => println!(
+            "{}",
+            format!("{}, --page {} for more", footer, query.page + 1).bright_black()
+        ),
+        _ => println!("{}", footer.bright_black()),
+    }
+    Ok(())
+}
+
+/// Writes the results to `path`, with the projected fields as columns or else the fields of the
+/// first document. Returns the number of documents written.
+pub async fn export(collection: &Collection<Document>, query: &AdhocQuery, path: &str, format: Format) -> Result<u64> {
+    let mut cursor = query.cursor(collection).await?;
+    let first = cursor.try_next().await?;
+    // Documents of a find need not share fields: NDJSON keeps each one whole, while CSV and XLSX
+    // take their columns from the projection or the first document.
+    let columns = match (query.projected_columns(), format) {
+        (Some(columns), _) => columns,
+        (None, Format::Ndjson) => Vec::new(),
+        (None, _) => first
+            .as_ref()
+            .map(|doc| doc.keys().cloned().collect())
+            .unwrap_or_default(),
+    };
+    let rows = stream::iter(first.map(Ok)).chain(cursor);
+    export::write(rows, &columns, path, format).await
+}
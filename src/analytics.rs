@@ -1,73 +1,365 @@
+use crate::error::Result;
+use crate::lenders::{self, LenderSpec};
+use crate::output;
+use crate::query;
+use crate::redact;
 use colored::*;
 use futures_util::stream::TryStreamExt;
-use mongodb::bson::{doc, DateTime, Document};
+use mongodb::bson::{doc, Bson, DateTime, Document};
 use mongodb::Collection;
-use serde_json::Value;
-use std::error::Error;
+use serde::Serialize;
 
-pub async fn duplicates(collection: &Collection<Document>) -> Result<(), Box<dyn Error>> {
+/// Duplicate phone numbers among users updated inside a window.
+#[derive(Debug, Default, Serialize)]
+pub struct DuplicateReport {
+    /// Phones shared by more than one user document.
+    pub duplicate_phones: f64,
+    /// User documents carrying one of those phones.
+    pub total_duplicates: f64,
+    /// Documents a merge would delete, keeping one per phone.
+    pub extra_documents: f64,
+    /// Group size label (`2`, `3`, `4-10`, `>10`) with the phones and documents in it.
+    pub group_sizes: Vec<GroupSize>,
+    /// Phones with the most documents, largest first.
+    pub top_phones: Vec<PhoneCount>,
+    /// Duplicate documents contributed by each partner, largest first.
+    pub partners: Vec<PartnerCount>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct GroupSize {
+    pub size: String,
+    pub phones: f64,
+    pub documents: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PhoneCount {
+    pub phone: String,
+    pub count: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PartnerCount {
+    pub partner: String,
+    pub users: f64,
+}
+
+impl DuplicateReport {
+    /// Labelled numbers for [`compare::render`](crate::compare::render).
+    pub fn metrics(&self) -> Vec<(String, f64)> {
+        let mut metrics = vec![
+            ("phones with duplicates".to_string(), self.duplicate_phones),
+            ("documents involved".to_string(), self.total_duplicates),
+            ("removed by a merge".to_string(), self.extra_documents),
+        ];
+        for size in &self.group_sizes {
+            metrics.push((format!("groups of {}", size.size), size.phones));
+        }
+        for partner in &self.partners {
+            metrics.push((format!("{} duplicate users", partner.partner), partner.users));
+        }
+        metrics
+    }
+
+    /// One record per reported number, tagged with the section it belongs to.
+    pub fn records(&self) -> Vec<Document> {
+        let mut records = vec![
+            doc! { "section": "summary", "key": "phones with duplicates", "count": self.duplicate_phones },
+            doc! { "section": "summary", "key": "documents involved", "count": self.total_duplicates },
+            doc! { "section": "summary", "key": "removed by a merge", "count": self.extra_documents },
+        ];
+        for size in &self.group_sizes {
+            records.push(
+                doc! { "section": "group size", "key": &size.size, "count": size.phones, "documents": size.documents },
+            );
+        }
+        for phone in &self.top_phones {
+            records.push(doc! { "section": "top phone", "key": &phone.phone, "count": phone.count });
+        }
+        for partner in &self.partners {
+            records.push(doc! { "section": "partner", "key": &partner.partner, "count": partner.users });
+        }
+        records
+    }
+}
+
+pub async fn duplicate_report(
+    collection: &Collection<Document>,
+    from: DateTime,
+    to: DateTime,
+    top: i64,
+) -> Result<DuplicateReport> {
     let pipeline = vec![
-        doc! {
-        "$match": { "updatedAt": {
-          "$gte": DateTime::parse_rfc3339_str("2024-05-15T00:00:00Z")?,
-          "$lt": DateTime::parse_rfc3339_str("2025-05-16T00:00:00Z")?
-        }}},
-        doc! { "$group": { "_id": "$phone", "count": { "$sum": 1 } } },
+        doc! { "$match": { "updatedAt": { "$gte": from, "$lt": to } } },
+        doc! { "$group": { "_id": "$phone", "count": { "$sum": 1 }, "partners": { "$push": "$partner" } } },
         doc! { "$match": { "count": { "$gt": 1 } } },
-        doc! { "$group": { "_id": "null", "duplicatePhones": { "$sum": 1 }, "totalDuplicates": { "$sum": "$count" } } },
+        doc! { "$facet": {
+            "summary": [
+                { "$group": { "_id": null, "duplicatePhones": { "$sum": 1 }, "totalDuplicates": { "$sum": "$count" } } },
+            ],
+            "sizes": [
+                { "$bucket": {
+                    "groupBy": "$count",
+                    "boundaries": [2, 3, 4, 11],
+                    "default": ">10",
+                    "output": { "phones": { "$sum": 1 }, "documents": { "$sum": "$count" } },
+                } },
+            ],
+            "top": [
+                { "$sort": { "count": -1 } },
+                { "$limit": top },
+            ],
+            "partners": [
+                { "$unwind": "$partners" },
+                { "$group": { "_id": "$partners", "users": { "$sum": 1 } } },
+                { "$sort": { "users": -1 } },
+            ],
+        } },
     ];
-    let mut cursor = collection.aggregate(pipeline, None).await?;
+    let mut cursor = query::aggregate(collection, pipeline, None).await?;
+    let mut report = DuplicateReport::default();
+    let Some(doc) = cursor.try_next().await? else {
+        return Ok(report);
+    };
 
-    while let Some(doc) = cursor.try_next().await? {
-        println!("{}", doc);
+    if let Some(summary) = doc.get_array("summary")?.first().and_then(Bson::as_document) {
+        report.duplicate_phones = as_f64(summary.get("duplicatePhones"));
+        report.total_duplicates = as_f64(summary.get("totalDuplicates"));
+        report.extra_documents = report.total_duplicates - report.duplicate_phones;
+    }
+    for size in doc.get_array("sizes")?.iter().filter_map(Bson::as_document) {
+        let label = match size.get("_id") {
+            Some(Bson::Int32(2)) => "2".to_string(),
+            Some(Bson::Int32(3)) => "3".to_string(),
+            Some(Bson::Int32(4)) => "4-10".to_string(),
+            Some(Bson::String(s)) => s.clone(),
+            other => format!("{:?}", other),
+        };
+        report.group_sizes.push(GroupSize {
+            size: label,
+            phones: as_f64(size.get("phones")),
+            documents: as_f64(size.get("documents")),
+        });
     }
-    Ok(())
+    for phone in doc.get_array("top")?.iter().filter_map(Bson::as_document) {
+        report.top_phones.push(PhoneCount {
+            phone: redact::phone(&label(phone.get("_id"))).into_owned(),
+            count: as_f64(phone.get("count")),
+        });
+    }
+    for partner in doc.get_array("partners")?.iter().filter_map(Bson::as_document) {
+        report.partners.push(PartnerCount {
+            partner: label(partner.get("_id")),
+            users: as_f64(partner.get("users")),
+        });
+    }
+    Ok(report)
 }
 
-pub async fn total_count(collection: &Collection<Document>, partner: &str) {
-    let count_mt_entries = collection
-        .count_documents(doc! { "partner": partner}, None)
-        .await
-        .unwrap();
-    let mt_entries_false = collection
-        .count_documents(doc! { "partner": partner, "partnerSent": false }, None)
-        .await
-        .unwrap();
-    let mt_entries_true = collection
-        .count_documents(doc! { "partner": partner, "partnerSent": true }, None)
-        .await
-        .unwrap();
-    let not_banned = collection
-        .count_documents(
-            doc! {
-                "partner": "MoneyTap",
-                "partnerSent": false,
-                "$or": [
-                    { "isBanned": false },
-                    { "isBanned": { "$exists": false } }
-                ]
-            },
-            None,
-        )
-        .await
-        .unwrap();
-
-    println!("MT Total: {}", count_mt_entries);
-    println!("MT Sent: {}", mt_entries_true);
-    println!("MT Pending: {}", mt_entries_false);
-    println!("MT notBanned: {}", not_banned);
+pub async fn duplicates(collection: &Collection<Document>, from: DateTime, to: DateTime, top: i64) -> Result<()> {
+    let report = duplicate_report(collection, from, to, top).await?;
+    output::emit(&report.records(), || {
+        println!("Duplicate phones ({} to {})", from, to);
+        println!(
+            "  {:<28} {}",
+            "phones with duplicates",
+            report.duplicate_phones.to_string().yellow()
+        );
+        println!(
+            "  {:<28} {}",
+            "documents involved",
+            report.total_duplicates.to_string().yellow()
+        );
+        println!(
+            "  {:<28} {}",
+            "removed by a merge",
+            report.extra_documents.to_string().yellow()
+        );
+
+        println!("\n{}", "Group sizes".bold());
+        println!("  {:<10} {:>10} {:>10}", "size", "phones", "documents");
+        for size in &report.group_sizes {
+            println!("  {:<10} {:>10} {:>10}", size.size.green(), size.phones, size.documents);
+        }
+
+        println!("\n{}", format!("Top {} phones", top).bold());
+        for phone in &report.top_phones {
+            println!("  {:<16} {:>6}", phone.phone.green(), phone.count);
+        }
+
+        println!("\n{}", "Duplicate users by partner".bold());
+        for partner in &report.partners {
+            println!("  {:<24} {:>10}", partner.partner.green(), partner.users);
+        }
+    })
 }
 
-pub async fn pipeline(collection: &Collection<Document>) -> Result<(), Box<dyn Error>> {
-    let match_stage = doc! {
-            "$match": {
-                "updatedAt": {
-                    "$gte": DateTime::parse_rfc3339_str("2024-05-15T00:00:00Z")?,
-                    "$lt": DateTime::parse_rfc3339_str("2024-05-16T00:00:00Z")?
-                }
-            }
+/// Display form of a grouped `_id` that may be missing, null or not a string.
+fn label(value: Option<&Bson>) -> String {
+    match value {
+        Some(Bson::String(s)) => s.clone(),
+        None | Some(Bson::Null) => "(none)".to_string(),
+        Some(other) => other.to_string(),
+    }
+}
 
+/// Partner send counts: total users, sent, pending and pending users who are not banned, optionally
+/// restricted to users updated inside a window.
+pub async fn partner_counts(
+    collection: &Collection<Document>,
+    partner: &str,
+    window: Option<(DateTime, DateTime)>,
+) -> Result<Vec<(String, f64)>> {
+    let scoped = |mut filter: Document| {
+        filter.insert("partner", partner);
+        if let Some((from, to)) = window {
+            filter.insert("updatedAt", doc! { "$gte": from, "$lt": to });
+        }
+        filter
     };
+
+    let count_mt_entries = query::count(collection, scoped(doc! {})).await?;
+    let mt_entries_false = query::count(collection, scoped(doc! { "partnerSent": false })).await?;
+    let mt_entries_true = query::count(collection, scoped(doc! { "partnerSent": true })).await?;
+    let not_banned = query::count(
+        collection,
+        scoped(doc! {
+            "partnerSent": false,
+            "$or": [
+                { "isBanned": false },
+                { "isBanned": { "$exists": false } }
+            ]
+        }),
+    )
+    .await?;
+
+    Ok(vec![
+        ("Total".to_string(), count_mt_entries as f64),
+        ("Sent".to_string(), mt_entries_true as f64),
+        ("Pending".to_string(), mt_entries_false as f64),
+        ("notBanned".to_string(), not_banned as f64),
+    ])
+}
+
+pub async fn total_count(
+    collection: &Collection<Document>,
+    partner: &str,
+    window: Option<(DateTime, DateTime)>,
+) -> Result<()> {
+    let records: Vec<Document> = partner_counts(collection, partner, window)
+        .await?
+        .into_iter()
+        .map(|(metric, count)| doc! { "partner": partner, "metric": metric, "count": count })
+        .collect();
+    output::emit(&records, || output::table(&records))
+}
+
+/// Top-level user fields kept in every flattened pipeline row, ahead of the lender columns.
+pub const PIPELINE_FIELDS: &[&str] = &[
+    "phone",
+    "name",
+    "partner",
+    "pincode",
+    "employment",
+    "createdAt",
+    "updatedAt",
+    "accounts_no",
+];
+
+/// Column order of a flattened pipeline row: [`PIPELINE_FIELDS`] followed by each lender's columns.
+pub fn pipeline_columns(specs: &[LenderSpec]) -> Vec<String> {
+    let mut columns: Vec<String> = PIPELINE_FIELDS.iter().map(|f| f.to_string()).collect();
+    columns.extend(specs.iter().flat_map(LenderSpec::columns));
+    columns
+}
+
+/// Filters, sort order and limit applied to the flattened pipeline rows.
+#[derive(Debug, Default)]
+pub struct PipelineQuery {
+    pub sort: Vec<(String, i32)>,
+    pub limit: Option<i64>,
+    pub partner: Option<String>,
+    /// Only users with an account at this lender.
+    pub lender: Option<LenderSpec>,
+    /// Only users whose `<lender>_status` equals this value; requires `lender`.
+    pub status: Option<String>,
+}
+
+impl PipelineQuery {
+    /// Validates the raw options against the columns the pipeline actually produces.
+    ///
+    /// `sort` is a comma separated list of columns, each optionally prefixed with `-` for
+    /// descending order, e.g. `-accounts_no,createdAt`. `lender` matches a spec's name or key.
+    pub fn new(
+        specs: &[LenderSpec],
+        sort: Option<&str>,
+        limit: Option<i64>,
+        partner: Option<String>,
+        lender: Option<&str>,
+        status: Option<String>,
+    ) -> Result<Self> {
+        let columns = pipeline_columns(specs);
+        let mut keys = Vec::new();
+        for key in sort
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|k| !k.is_empty())
+        {
+            let (field, direction) = match key.strip_prefix('-') {
+                Some(field) => (field, -1),
+                None => (key.strip_prefix('+').unwrap_or(key), 1),
+            };
+            if !columns.iter().any(|c| c == field) {
+                return Err(format!("unknown sort key {:?}; expected one of: {}", field, columns.join(", ")).into());
+            }
+            keys.push((field.to_string(), direction));
+        }
+
+        if limit.is_some_and(|l| l <= 0) {
+            return Err("--limit must be greater than zero".into());
+        }
+
+        let lender = match lender {
+            Some(lender) => Some(
+                specs
+                    .iter()
+                    .find(|s| s.name.eq_ignore_ascii_case(lender) || s.key.eq_ignore_ascii_case(lender))
+                    .cloned()
+                    .ok_or_else(|| format!("unknown lender {:?}", lender))?,
+            ),
+            None => None,
+        };
+        match (&lender, &status) {
+            (None, Some(_)) => return Err("--status requires --lender".into()),
+            (Some(spec), Some(_)) if spec.status.is_none() => {
+                return Err(format!("lender {:?} has no status field in the lender spec", spec.name).into())
+            }
+            _ => {}
+        }
+
+        Ok(PipelineQuery {
+            sort: keys,
+            limit,
+            partner,
+            lender,
+            status,
+        })
+    }
+}
+
+/// Stages producing one flattened row per user updated inside the window, filtered, sorted and
+/// limited according to `query`.
+pub fn pipeline_stages(specs: &[LenderSpec], from: DateTime, to: DateTime, query: &PipelineQuery) -> Vec<Document> {
+    let mut filter = doc! { "updatedAt": { "$gte": from, "$lt": to } };
+    if let Some(partner) = &query.partner {
+        filter.insert("partner", partner.as_str());
+    }
+    if let Some(lender) = &query.lender {
+        filter.insert("accounts.name", lender.name.as_str());
+    }
+    let match_stage = doc! { "$match": filter };
     let project_stage = doc! {
         "$project": {
             "phone": 1,
@@ -81,506 +373,258 @@ pub async fn pipeline(collection: &Collection<Document>) -> Result<(), Box<dyn E
         },
     };
 
-    let add_fields_stage = doc! {
-        "$addFields": {
-            "accounts_no": {
-                "$cond": {
-                    "if": { "$isArray": "$accounts" },
-                    "then": { "$size": "$accounts" },
-                    "else": 0,
-                },
-            },
-            /* "accounts_names": {
-                "$reduce": {
-                    "input": "$accounts",
-                    "initialValue": "",
-                    "in": { "$concat": [ "$$value", { "$cond": [ { "$eq": [ "$$value", "" ] }, "", " " ] }, "$$this.name" ] },
-                },
-            }, */
-            "cashe_details": {
-                "$arrayElemAt": [
-                    {
-                        "$filter": {
-                            "input": "$accounts",
-                            "as": "account",
-                            "cond": { "$eq": ["$$account.name", "Cashe"] },
-                        },
-                    },
-                    0,
-                ],
-            },
-            "fibe_details": {
-                "$arrayElemAt": [
-                    {
-                        "$filter": {
-                            "input": "$accounts",
-                            "as": "account",
-                            "cond": { "$eq": ["$$account.name", "Fibe"] },
-                        },
-                    },
-                    0,
-                ],
-            },
-            "moneyview_details": {
-                "$arrayElemAt": [
-                    {
-                        "$filter": {
-                            "input": "$accounts",
-                            "as": "account",
-                            "cond": { "$eq": ["$$account.name", "MoneyView"] },
-                        },
-                    },
-                    0,
-                ],
-            },
-            "payme_details": {
-                "$arrayElemAt": [
-                    {
-                        "$filter": {
-                            "input": "$accounts",
-                            "as": "account",
-                            "cond": { "$eq": ["$$account.name", "Payme"] },
-                        },
-                    },
-                    0,
-                ],
-            },
-            "upwards_details": {
-                "$arrayElemAt": [
-                    {
-                        "$filter": {
-                            "input": "$accounts",
-                            "as": "account",
-                            "cond": { "$eq": ["$$account.name", "Upwards"] },
-                        },
-                    },
-                    0,
-                ],
-            },
-            "prefr_details": {
-                "$arrayElemAt": [
-                    {
-                        "$filter": {
-                            "input": "$accounts",
-                            "as": "account",
-                            "cond": { "$eq": ["$$account.name", "Prefr"] },
-                        },
-                    },
-                    0,
-                ],
-            },
-            "lk_details": {
-                "$arrayElemAt": [
-                    {
-                        "$filter": {
-                            "input": "$accounts",
-                            "as": "account",
-                            "cond": { "$eq": ["$$account.name", "LendingKart"] },
-                        },
-                    },
-                    0,
-                ],
-            },
-            "faircent_details": {
-                "$arrayElemAt": [
-                    {
-                        "$filter": {
-                            "input": "$accounts",
-                            "as": "account",
-                            "cond": { "$eq": ["$$account.name", "Faircent"] },
-                        },
-                    },
-                    0,
-                ],
-            },
-            "zype_details": {
-                "$arrayElemAt": [
-                    {
-                        "$filter": {
-                            "input": "$accounts",
-                            "as": "account",
-                            "cond": { "$eq": ["$$account.name", "Zype"] },
-                        },
-                    },
-                    0,
-                ],
-            },
-            "loantap_details": {
-                "$arrayElemAt": [
-                    {
-                        "$filter": {
-                            "input": "$accounts",
-                            "as": "account",
-                            "cond": { "$eq": ["$$account.name", "LoanTap"] },
-                        },
-                    },
-                    0,
-                ],
-            },
-            "um_details": {
-                "$arrayElemAt": [
-                    {
-                        "$filter": {
-                            "input": "$accounts",
-                            "as": "account",
-                            "cond": { "$eq": ["$$account.name", "Upwards MarketPlace"] },
-                        },
-                    },
-                    0,
-                ],
-            },
-            "mpocket_details": {
-                "$arrayElemAt": [
-                    {
-                        "$filter": {
-                            "input": "$accounts",
-                            "as": "account",
-                            "cond": { "$eq": ["$$account.name", "Mpocket"] },
-                        },
-                    },
-                    0,
-                ],
-            },
-        },
-    };
-
-    let add_fields_stage_2 = doc! {
-        "$addFields": {
-            "cashe_status": {
-                "$cond": {
-                    "if": { "$gt": ["$cashe_details.status", null] },
-                    "then": "$cashe_details.status",
-                    "else": "$$REMOVE",
-                }
-            },
-            "cashe_id": {
-                "$cond": {
-                    "if": { "$gt": ["$cashe_details.id", null] },
-                    "then": "$cashe_details.id",
-                    "else": "$$REMOVE",
-                }
-            },
-            "cashe_loanAmount": {
-                "$cond": {
-                    "if": { "$gt": ["$cashe_details.amount", null] },
-                    "then": "$cashe_details.amount",
-                    "else": "$$REMOVE",
-                }
-            },
-            "fibe_status": {
-                "$cond": {
-                    "if": { "$gt": ["$fibe_details.status", null] },
-                    "then": "$fibe_details.status",
-                    "else": "$$REMOVE",
-                }
-            },
-            "fibe_id": {
-                "$cond": {
-                    "if": {
-                        "$and": [
-                            { "$ne": ["$fibe_details.id", null] },
-                            { "$ne": ["$fibe_details.id", "null"] },
-                        ]
-                    },
-                    "then": "$fibe_details.id",
-                    "else": "$$REMOVE",
-                }
-            },
-            "fibe_loanAmount": {
-                "$cond": {
-                    "if": {
-                        "$and": [
-                            { "$ne": ["$fibe_details.loanAmount", 0] },
-                            { "$gt": ["$fibe_details.loanAmount", null] },
-                        ]
-                    },
-                    "then": "$fibe_details.loanAmount",
-                    "else": "$$REMOVE",
-                }
-            },
-            "payme_status": {
-                "$cond": {
-                    "if": { "$gt": ["$payme_details.msg", null] },
-                    "then": "$payme_details.msg",
-                    "else": "$$REMOVE",
-                }
-            },
-            "payme_id": {
-                "$cond": {
-                    "if": { "$gt": ["$payme_details.user_id", null] },
-                    "then": "$payme_details.user_id",
-                    "else": "$$REMOVE",
-                }
-            },
-            "payme_loanAmount": {
-                "$cond": {
-                    "if": {
-                        "$and": [
-                            { "$ne": [{ "$arrayElemAt": ["$payme_details.limit.credit_limit", 0] }, 0] },
-                            { "$gt": [{ "$arrayElemAt": ["$payme_details.limit.credit_limit", 0] }, null] },
-                        ]
-                    },
-                    "then": { "$arrayElemAt": ["$payme_details.limit.credit_limit", 0] },
-                    "else": "$$REMOVE",
-                }
-            },
-            "moneyview_status": {
-                "$cond": {
-                    "if": { "$gt": ["$moneyview_details.message", null] },
-                    "then": "$moneyview_details.message",
-                    "else": "$$REMOVE",
-                }
-            },
-            "moneyview_id": {
-                "$cond": {
-                    "if": { "$gt": ["$moneyview_details.id", null] },
-                    "then": "$moneyview_details.id",
-                    "else": "$$REMOVE",
-                }
-            },
-            "moneyview_loanAmount": {
-                "$cond": {
-                    "if": {
-                        "$and": [
-                            { "$ne": [{ "$arrayElemAt": ["$moneyview_details.offers.loanAmount", 0] }, 0] },
-                            { "$gt": [{ "$arrayElemAt": ["$moneyview_details.offers.loanAmount", 0] }, null] },
-                        ]
-                    },
-                    "then": { "$arrayElemAt": ["$moneyview_details.offers.loanAmount", 0] },
-                    "else": "$$REMOVE",
-                }
-            },
-            "upwards_status": {
-                "$cond": {
-                    "if": { "$gt": ["$upwards_details.status", null] },
-                    "then": "$upwards_details.status",
-                    "else": "$$REMOVE",
-                }
-            },
-            "upwards_id": {
-                "$cond": {
-                    "if": { "$gt": ["$upwards_details.id", null] },
-                    "then": "$upwards_details.id",
-                    "else": "$$REMOVE",
-                }
-            },
-            "prefr_status": {
-                "$cond": {
-                    "if": { "$gt": ["$prefr_details.response.eventName", null] },
-                    "then": "$prefr_details.response.eventName",
-                    "else": "$$REMOVE",
-                }
-            },
-            "prefr_id": {
-                "$cond": {
-                    "if": { "$gt": ["$prefr_details.id", null] },
-                    "then": "$prefr_details.id",
-                    "else": "$$REMOVE",
-                }
-            },
-            "lendingkart_status": {
-                "$cond": {
-                    "if": { "$gt": ["$lk_details.message", null] },
-                    "then": "$lk_details.message",
-                    "else": "$$REMOVE",
-                }
-            },
-            "lendingkart_id": {
-                "$cond": {
-                    "if": { "$gt": ["$lk_details.leadId", null] },
-                    "then": "$lk_details.leadId",
-                    "else": "$$REMOVE",
-                }
-            },
-            "faircent_status": {
-                "$cond": {
-                    "if": { "$gt": ["$faircent_details.status", null] },
-                    "then": "$faircent_details.status",
-                    "else": "$$REMOVE",
-                }
-            },
-            "faircent_id": {
-                "$cond": {
-                    "if": { "$gt": ["$faircent_details.id", null] },
-                    "then": "$faircent_details.id",
-                    "else": "$$REMOVE",
-                }
-            },
-            "faircent_loanAmount": {
-                "$cond": {
-                    "if": {
-                        "$and": [
-                            { "$ne": ["$faircent_details.res.result.offer_amount", 0] },
-                            { "$gt": ["$faircent_details.res.result.offer_amount", null] },
-                        ]
-                    },
-                    "then": "$faircent_details.res.result.offer_amount",
-                    "else": "$$REMOVE",
-                }
-            },
-            "zype_status": {
-                "$cond": {
-                    "if": { "$gt": ["$zype_details.status", null] },
-                    "then": "$zype_details.status",
-                    "else": "$$REMOVE",
-                }
-            },
-            "zype_loanAmount": {
-                "$cond": {
-                    "if": {
-                        "$and": [
-                            { "$ne": ["$zype_details.offer", 0] },
-                            { "$gt": ["$zype_details.offer", null] },
-                        ]
-                    },
-                    "then": "$zype_details.offer",
-                    "else": "$$REMOVE",
-                }
-            },
-            "loantap_status": {
-                "$cond": {
-                    "if": { "$gt": ["$loantap_details.message", null] },
-                    "then": "$loantap_details.message",
-                    "else": "$$REMOVE",
-                }
-            },
-            "loantap_id": {
-                "$cond": {
-                    "if": { "$gt": ["$loantap_details.data.lapp_id", null] },
-                    "then": "$loantap_details.data.lapp_id",
-                    "else": "$$REMOVE",
-                }
-            },
-            "upwards_marketplace_status": {
-                "$cond": {
-                    "if": { "$ifNull": ["$um_details.data.is_success", null] },
-                    "then": {
-                        "$cond": {
-                            "if": "$um_details.data.is_success",
-                            "then": "success",
-                            "else": "failure",
-                        }
-                    },
-                    "else": "$$REMOVE",
-                }
-            },
-            "upwards_marketplace_id": {
-                "$cond": {
-                    "if": { "$gt": ["$um_details.data.loan_data.customer_id", null] },
-                    "then": "$um_details.data.loan_data.customer_id",
-                    "else": "$$REMOVE",
-                }
-            },
-            "mpocket_status": {
-                "$cond": {
-                    "if": { "$ifNull": ["$mpocket_details.success", null] },
-                    "then": {
-                        "$cond": {
-                            "if": "$mpocket_details.success",
-                            "then": "success",
-                            "else": "failure",
-                        }
-                    },
-                    "else": "$$REMOVE",
-                }
-            },
-            "mpocket_id": {
-                "$cond": {
-                    "if": { "$gt": ["$mpocket_details.data.requestId", null] },
-                    "then": "$mpocket_details.data.requestId",
-                    "else": "$$REMOVE",
-                }
-            },
+    let mut pipeline: Vec<Document> = vec![match_stage, project_stage];
+    pipeline.extend(lenders::stages(specs));
+    if let (Some(lender), Some(status)) = (&query.lender, &query.status) {
+        pipeline.push(doc! { "$match": { lender.status_field(): status.as_str() } });
+    }
+    pipeline.push(lenders::cleanup_stage(specs));
+    if !query.sort.is_empty() {
+        let mut sort = Document::new();
+        for (field, direction) in &query.sort {
+            sort.insert(field.as_str(), *direction);
         }
-    };
-
-    let project_stage_2 = doc! {
-            "$project": {
-            "_id": 0,
-            "accounts": 0,
-            "cashe_details": 0,
-            "fibe_details": 0,
-            "payme_details": 0,
-            "moneyview_details": 0,
-            "upwards_details": 0,
-            "prefr_details": 0,
-            "lk_details": 0,
-            "faircent_details": 0,
-            "zype_details": 0,
-            "loantap_details": 0,
-            "um_details": 0,
-            "mpocket_details": 0,
-        },
-    };
-
-    let sort_stage = doc! { "$sort": { "accounts_size": -1, "createdAt": -1 } };
+        pipeline.push(doc! { "$sort": sort });
+    }
+    if let Some(limit) = query.limit {
+        pipeline.push(doc! { "$limit": limit });
+    }
+    pipeline
+}
 
-    let limit_stage = doc! { "$limit": 5 };
+pub async fn pipeline(
+    collection: &Collection<Document>,
+    specs: &[LenderSpec],
+    from: DateTime,
+    to: DateTime,
+    query: &PipelineQuery,
+) -> Result<()> {
+    let pipeline = pipeline_stages(specs, from, to, query);
+    let mut cursor = query::aggregate(collection, pipeline, None).await?;
 
-    let pipeline: Vec<Document> = vec![
-        match_stage,
-        project_stage,
-        add_fields_stage,
-        add_fields_stage_2,
-        project_stage_2,
-        sort_stage,
-        limit_stage,
-    ];
+    let mut printer = output::Printer::new(pipeline_columns(specs));
+    while let Some(doc) = cursor.try_next().await? {
+        printer.record(&doc)?;
+    }
+    printer.finish()
+}
 
-    let mut cursor = collection.aggregate(pipeline, None).await?;
+/// Funnel numbers for one lender inside a report window.
+#[derive(Debug)]
+pub struct LenderFunnel {
+    pub lender: String,
+    pub submitted: f64,
+    /// Distinct status values with their user counts, most common first.
+    pub statuses: Vec<(String, f64)>,
+    /// Users with a non-zero offer amount.
+    pub offers: f64,
+    pub total: f64,
+}
 
-    while let Some(mut doc) = cursor.try_next().await? {
-        if let Some(created_at) = doc.get("createdAt").and_then(|v| v.as_datetime()) {
-            if let Ok(created_at_str) = created_at.try_to_rfc3339_string() {
-                doc.insert("createdAt", created_at_str);
-            }
+impl LenderFunnel {
+    pub fn average(&self) -> f64 {
+        if self.offers > 0.0 {
+            self.total / self.offers
+        } else {
+            0.0
         }
+    }
+}
 
-        if let Some(updated_at) = doc.get("updatedAt").and_then(|v| v.as_datetime()) {
-            if let Ok(updated_at_str) = updated_at.try_to_rfc3339_string() {
-                doc.insert("updatedAt", updated_at_str);
-            }
-        }
+pub async fn lender_funnel(
+    collection: &Collection<Document>,
+    specs: &[LenderSpec],
+    partner: &str,
+    from: DateTime,
+    to: DateTime,
+) -> Result<Vec<LenderFunnel>> {
+    let lender_entries: Vec<Bson> = specs
+        .iter()
+        .map(|spec| {
+            Bson::Document(doc! {
+                "name": spec.name.as_str(),
+                "submitted": lenders::submitted(spec),
+                "status": format!("${}", spec.status_field()),
+                "amount": format!("${}", spec.amount_field()),
+            })
+        })
+        .collect();
+    let amount = doc! { "$convert": { "input": "$lenders.amount", "to": "double", "onError": null, "onNull": null } };
 
-        let json_value: Value = serde_json::to_value(&doc)?;
+    let mut pipeline: Vec<Document> = vec![
+        doc! { "$match": { "partner": partner, "updatedAt": { "$gte": from, "$lt": to } } },
+        doc! { "$project": { "accounts": 1 } },
+    ];
+    pipeline.extend(lenders::stages(specs));
+    pipeline.extend([
+        doc! { "$project": { "_id": 0, "lenders": lender_entries } },
+        doc! { "$unwind": "$lenders" },
+        doc! { "$match": { "lenders.submitted": true } },
+        doc! { "$group": {
+            "_id": { "lender": "$lenders.name", "status": "$lenders.status" },
+            "count": { "$sum": 1 },
+            "offers": { "$sum": { "$cond": [{ "$gt": [amount.clone(), 0] }, 1, 0] } },
+            "total": { "$sum": amount },
+        } },
+        doc! { "$group": {
+            "_id": "$_id.lender",
+            "submitted": { "$sum": "$count" },
+            "statuses": { "$push": { "status": { "$ifNull": ["$_id.status", "(none)"] }, "count": "$count" } },
+            "offers": { "$sum": "$offers" },
+            "total": { "$sum": "$total" },
+        } },
+        doc! { "$sort": { "submitted": -1 } },
+    ]);
 
-        let colorized_json = colorize_json(&json_value, 0);
+    let mut cursor = query::aggregate(collection, pipeline, None).await?;
 
-        println!("{}", colorized_json);
+    let mut funnels = Vec::new();
+    while let Some(doc) = cursor.try_next().await? {
+        let mut statuses: Vec<(String, f64)> = doc
+            .get_array("statuses")
+            .map(|s| s.iter().filter_map(|v| v.as_document()).map(status_count).collect())
+            .unwrap_or_default();
+        statuses.sort_by(|a, b| b.1.total_cmp(&a.1));
+        funnels.push(LenderFunnel {
+            lender: doc.get_str("_id").unwrap_or("?").to_string(),
+            submitted: as_f64(doc.get("submitted")),
+            statuses,
+            offers: as_f64(doc.get("offers")),
+            total: as_f64(doc.get("total")),
+        });
     }
-    Ok(())
+    Ok(funnels)
+}
+
+/// Flattens funnels into labelled numbers for [`compare::render`](crate::compare::render).
+pub fn lender_metrics(funnels: &[LenderFunnel]) -> Vec<(String, f64)> {
+    funnels
+        .iter()
+        .flat_map(|f| {
+            [
+                (format!("{} submitted", f.lender), f.submitted),
+                (format!("{} offers", f.lender), f.offers),
+                (format!("{} avg amount", f.lender), f.average()),
+                (format!("{} total amount", f.lender), f.total),
+            ]
+        })
+        .collect()
 }
 
-pub fn colorize_json(json: &Value, indent: usize) -> String {
-    match json {
-        Value::Object(map) => {
-            let contents: Vec<String> = map
+pub async fn lenders(
+    collection: &Collection<Document>,
+    specs: &[LenderSpec],
+    partner: &str,
+    from: DateTime,
+    to: DateTime,
+) -> Result<()> {
+    let funnels = lender_funnel(collection, specs, partner, from, to).await?;
+    let records: Vec<Document> = funnels
+        .iter()
+        .map(|funnel| {
+            let statuses: Document = funnel
+                .statuses
                 .iter()
-                .map(|(k, v)| {
-                    format!(
-                        "{}{}: {}",
-                        "  ".repeat(indent + 1),
-                        k.green(),
-                        colorize_json(v, indent + 1)
-                    )
-                })
+                .map(|(status, count)| (status.clone(), Bson::Double(*count)))
                 .collect();
-            format!("{{\n{}\n{}}}", contents.join(",\n"), "  ".repeat(indent))
-        }
-        Value::Array(arr) => {
-            let contents: Vec<String> = arr.iter().map(|v| colorize_json(v, indent + 1)).collect();
-            format!("[{}]", contents.join(", "))
-        }
-        /* date */
-        Value::String(s) => {
-            if s.starts_with("20") && s.len() > 20 {
-                s.purple().to_string()
-            } else {
-                format!("'{}'", s.cyan())
+            doc! {
+                "lender": &funnel.lender,
+                "submitted": funnel.submitted,
+                "offers": funnel.offers,
+                "average_amount": funnel.average(),
+                "total_amount": funnel.total,
+                "statuses": statuses,
+            }
+        })
+        .collect();
+    output::emit(&records, || {
+        println!("Lender funnel for {} ({} to {})", partner.cyan(), from, to);
+        for funnel in &funnels {
+            println!(
+                "\n{}  submitted: {}  offers: {}  avg: {:.2}  total: {:.2}",
+                funnel.lender.green().bold(),
+                funnel.submitted.to_string().yellow(),
+                funnel.offers.to_string().yellow(),
+                funnel.average(),
+                funnel.total
+            );
+            for (status, count) in &funnel.statuses {
+                println!("    {:<40} {}", status, count);
             }
         }
-        Value::Number(n) => n.to_string().yellow().to_string(),
-        Value::Bool(b) => b.to_string().yellow().to_string(),
-        Value::Null => "null".bright_black().to_string(),
+    })
+}
+
+fn status_count(doc: &Document) -> (String, f64) {
+    let status = match doc.get("status") {
+        Some(Bson::String(s)) => s.clone(),
+        Some(other) => other.to_string(),
+        None => "(none)".to_string(),
+    };
+    (status, as_f64(doc.get("count")))
+}
+
+pub fn as_f64(value: Option<&Bson>) -> f64 {
+    match value {
+        Some(Bson::Int32(n)) => *n as f64,
+        Some(Bson::Int64(n)) => *n as f64,
+        Some(Bson::Double(n)) => *n,
+        _ => 0.0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lenders;
+
+    fn query(sort: &str, lender: Option<&str>, status: Option<&str>) -> Result<PipelineQuery> {
+        let specs = lenders::load(None).unwrap();
+        PipelineQuery::new(&specs, Some(sort), None, None, lender, status.map(str::to_string))
+    }
+
+    #[test]
+    fn sort_prefixes_set_the_direction() {
+        let query = query("-accounts_no,+createdAt,fibe_status", None, None).unwrap();
+        assert_eq!(
+            query.sort,
+            vec![
+                ("accounts_no".to_string(), -1),
+                ("createdAt".to_string(), 1),
+                ("fibe_status".to_string(), 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_sort_key_is_rejected() {
+        let error = query("-accounts_no,nope", None, None).err().unwrap();
+        assert!(error.to_string().contains("unknown sort key \"nope\""), "{}", error);
+    }
+
+    #[test]
+    fn status_requires_a_lender() {
+        let error = query("", None, Some("approved")).err().unwrap();
+        assert_eq!(error.to_string(), "--status requires --lender");
+    }
+
+    #[test]
+    fn lender_matches_name_or_key() {
+        let query = query("", Some("upwards_marketplace"), Some("success")).unwrap();
+        assert_eq!(query.lender.unwrap().name, "Upwards MarketPlace");
+    }
+
+    #[test]
+    fn status_needs_a_lender_with_a_status_field() {
+        let specs: Vec<LenderSpec> =
+            serde_json::from_str(r#"[{ "name": "Acme", "key": "acme", "id": { "path": "id" } }]"#).unwrap();
+        let error = PipelineQuery::new(&specs, None, None, None, Some("acme"), Some("ok".to_string()))
+            .err()
+            .unwrap();
+        assert_eq!(
+            error.to_string(),
+            "lender \"Acme\" has no status field in the lender spec"
+        );
+        assert!(PipelineQuery::new(&specs, None, None, None, Some("acme"), None).is_ok());
     }
 }
@@ -0,0 +1,100 @@
+use crate::error::Result;
+use crate::export::cell;
+use crate::output;
+use crate::query;
+use colored::*;
+use futures_util::stream::TryStreamExt;
+use mongodb::bson::{doc, Document, Regex};
+use mongodb::options::FindOptions;
+use mongodb::Collection;
+
+/// Data problems that break the merge or the reports, as `(description, filter)` pairs.
+fn checks() -> Vec<(&'static str, Document)> {
+    let regex = |pattern: &str| Regex {
+        pattern: pattern.to_string(),
+        options: String::new(),
+    };
+    vec![
+        (
+            "phone missing or not a 10 digit mobile number",
+            doc! { "phone": { "$not": regex("^[6-9][0-9]{9}$") } },
+        ),
+        (
+            "updatedAt missing or not a date",
+            doc! { "updatedAt": { "$not": { "$type": "date" } } },
+        ),
+        (
+            "createdAt missing or not a date",
+            doc! { "createdAt": { "$not": { "$type": "date" } } },
+        ),
+        (
+            "updatedAt earlier than createdAt",
+            doc! {
+                "createdAt": { "$type": "date" },
+                "updatedAt": { "$type": "date" },
+                "$expr": { "$lt": ["$updatedAt", "$createdAt"] },
+            },
+        ),
+        (
+            "accounts is not an array",
+            doc! { "accounts": { "$exists": true, "$not": { "$type": "array" } } },
+        ),
+        (
+            "pincode is not six digits",
+            doc! {
+                "pincode": { "$exists": true },
+                "$expr": { "$not": [{ "$regexMatch": {
+                    "input": { "$convert": { "input": "$pincode", "to": "string", "onError": "", "onNull": "" } },
+                    "regex": "^[1-9][0-9]{5}$",
+                } }] },
+            },
+        ),
+        (
+            "partnerSent stored as a string",
+            doc! { "partnerSent": { "$type": "string" } },
+        ),
+        (
+            "isBanned stored as a string",
+            doc! { "isBanned": { "$type": "string" } },
+        ),
+    ]
+}
+
+/// Counts users failing each check and lists up to `samples` of their `_id`s.
+pub async fn audit(collection: &Collection<Document>, samples: i64) -> Result<()> {
+    let total = collection.estimated_document_count(None).await?;
+
+    let mut records = Vec::new();
+    for (description, filter) in checks() {
+        let count = query::count(collection, filter.clone()).await?;
+        let mut ids: Vec<String> = Vec::new();
+        if count > 0 {
+            let options = FindOptions::builder()
+                .projection(doc! { "_id": 1 })
+                .limit(samples)
+                .build();
+            ids = query::find(collection, filter, options)
+                .await?
+                .map_ok(|doc| cell(doc.get("_id")))
+                .try_collect()
+                .await?;
+        }
+        records.push(doc! { "check": description, "count": count as i64, "samples": ids });
+    }
+
+    output::emit(&records, || {
+        println!("Auditing {} (~{} documents)", collection.name().cyan(), total);
+        for record in &records {
+            let count = record.get_i64("count").unwrap_or_default();
+            let status = if count == 0 {
+                "ok".green()
+            } else {
+                count.to_string().red().bold()
+            };
+            println!("\n{:<50} {}", record.get_str("check").unwrap_or_default(), status);
+            for id in record.get_array("samples").into_iter().flatten() {
+                println!("    {}", cell(Some(id)).bright_black());
+            }
+        }
+    })
+}
@@ -0,0 +1,34 @@
+use colored::*;
+
+const SPARKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+/// Horizontal bar scaled so that `max` fills `width` cells.
+pub fn bar(value: f64, max: f64, width: usize) -> String {
+    if max <= 0.0 || value <= 0.0 {
+        return String::new();
+    }
+    let cells = ((value / max) * width as f64).round().max(1.0) as usize;
+    "█".repeat(cells.min(width)).blue().to_string()
+}
+
+/// One character per value, scaled between the smallest and largest value.
+pub fn sparkline(values: &[f64]) -> String {
+    let min = values.iter().copied().fold(f64::INFINITY, f64::min);
+    let max = values.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    let line: String = values
+        .iter()
+        .map(|v| {
+            if max > min {
+                SPARKS[(((v - min) / (max - min)) * (SPARKS.len() - 1) as f64).round() as usize]
+            } else {
+                SPARKS[SPARKS.len() / 2]
+            }
+        })
+        .collect();
+    line.blue().to_string()
+}
+
+/// First `width` characters of `s`, for fixed-width table cells.
+pub fn truncate(s: &str, width: usize) -> String {
+    s.chars().take(width).collect()
+}
@@ -0,0 +1,389 @@
+use crate::config::ConcernLevel;
+use crate::logging::LogFormat;
+use crate::timeseries::{Chart, Unit};
+use crate::{compare, export, output};
+use clap::{ArgAction, Args, Parser, Subcommand, ValueEnum};
+use mongodb::bson::DateTime;
+
+#[derive(Debug, Parser)]
+#[command(
+    name = "mongo-utils",
+    version = "1.0",
+    about = "Merges, reports and queries on the users collection"
+)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Commands,
+
+    /// Output format; table, json, ndjson, csv or yaml on stdout (default table), csv, ndjson or xlsx with --output (default csv)
+    #[arg(long, global = true, value_enum)]
+    pub format: Option<Format>,
+
+    /// Shorthand for --format json
+    #[arg(long, global = true, conflicts_with = "format")]
+    pub json: bool,
+
+    /// Masks phones, reduces names to initials and hashes --hash fields in all output, logs and exports
+    #[arg(long, global = true)]
+    pub redact: bool,
+
+    /// Field name or dotted path replaced by a salted hash under --redact (salt from REDACT_SALT, repeatable)
+    #[arg(long = "hash", value_name = "FIELD", global = true, requires = "redact")]
+    pub hashed: Vec<String>,
+
+    /// Prints the plan summary of every query instead of running it
+    #[arg(long, global = true)]
+    pub explain: bool,
+
+    /// Refuses to run any query estimated from its plan to examine more documents than this
+    #[arg(long, value_name = "N", global = true)]
+    pub max_examined: Option<u64>,
+
+    /// Runs queries over --max-examined anyway
+    #[arg(long, global = true)]
+    pub force: bool,
+
+    /// JSON file with connection profiles (defaults to profiles.json when it exists)
+    #[arg(long, value_name = "FILE", global = true, env = "MONGO_UTILS_CONFIG")]
+    pub config: Option<String>,
+
+    /// Connection profile from the config file
+    #[arg(long, value_name = "NAME", global = true, env = "MONGO_PROFILE")]
+    pub profile: Option<String>,
+
+    /// Database to use instead of the profile's
+    #[arg(long = "db", value_name = "NAME", global = true)]
+    pub database: Option<String>,
+
+    /// Collection to use instead of the profile's
+    #[arg(long, value_name = "NAME", global = true)]
+    pub collection: Option<String>,
+
+    /// Read concern of every read-only command, instead of the profile's or the server default
+    #[arg(long, value_enum, value_name = "LEVEL", global = true)]
+    pub read_concern: Option<ConcernLevel>,
+
+    /// Seconds a secondary may lag and still serve reads (at least 90), instead of the profile's
+    #[arg(long, value_name = "SECONDS", global = true, value_parser = clap::value_parser!(u64).range(90..))]
+    pub max_staleness: Option<u64>,
+
+    /// More diagnostics: -v for debug (every query), -vv for trace
+    #[arg(short, long, action = ArgAction::Count, global = true, conflicts_with = "quiet")]
+    pub verbose: u8,
+
+    /// Fewer diagnostics: -q for warnings and errors only, -qq for errors only. The records of
+    /// what a merge wrote are always kept
+    #[arg(short, long, action = ArgAction::Count, global = true)]
+    pub quiet: u8,
+
+    /// Format of the diagnostics
+    #[arg(long, value_enum, default_value_t = LogFormat::Text, global = true)]
+    pub log_format: LogFormat,
+
+    /// Appends diagnostics to this file instead of stderr
+    #[arg(long, value_name = "FILE", global = true)]
+    pub log_file: Option<String>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Commands {
+    /// Merges users sharing a phone, one phone at a time
+    Merge {
+        #[command(flatten)]
+        confirm: Confirm,
+    },
+    /// Merges up to LIMIT duplicate phones concurrently
+    Merge2 {
+        /// Number of duplicate phones to merge
+        #[arg(value_parser = clap::value_parser!(u32).range(1..))]
+        limit: u32,
+        #[command(flatten)]
+        confirm: Confirm,
+    },
+    /// Duplicate phone numbers among users updated in the window
+    Duplicates {
+        #[command(flatten)]
+        window: Window,
+        /// Number of phones listed
+        #[arg(long, default_value_t = 10, value_parser = clap::value_parser!(i64).range(1..))]
+        top: i64,
+        #[command(flatten)]
+        compare: Compare,
+    },
+    /// Flattened user rows with one set of columns per lender
+    Pipeline(PipelineArgs),
+    /// Runs an ad-hoc find (--filter, --projection, --sort) or aggregation (--agg)
+    Query(QueryArgs),
+    /// Runs the data quality audit of the users collection
+    Audit {
+        /// Sample _ids listed per failed check
+        #[arg(long, value_name = "N", default_value_t = 5, value_parser = clap::value_parser!(i64).range(1..))]
+        samples: i64,
+    },
+    /// Lists indexes with size and usage and suggests missing ones
+    Indexes {
+        /// Creates the suggested indexes that are missing
+        #[arg(long)]
+        apply: bool,
+    },
+    /// Infers field paths, BSON types and presence from a sample of users
+    Schema {
+        /// Users sampled
+        #[arg(long, value_name = "N", default_value_t = 1000, value_parser = clap::value_parser!(i64).range(1..))]
+        sample_size: i64,
+    },
+    /// Built-in and saved reports
+    #[command(subcommand)]
+    Report(Report),
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Report {
+    /// Total, sent, pending and not banned users of a partner (all time unless --from/--to is given;
+    /// --compare-to needs both)
+    Partners {
+        /// Partner to count, defaults to PARTNER or the profile's partner
+        #[arg(long, env = "PARTNER")]
+        partner: Option<String>,
+        #[command(flatten)]
+        window: Window,
+        #[command(flatten)]
+        compare: Compare,
+    },
+    /// Lender outcome funnel of a partner's users
+    Lenders {
+        /// Partner to report on, defaults to PARTNER or the profile's partner
+        #[arg(long, env = "PARTNER")]
+        partner: Option<String>,
+        #[command(flatten)]
+        window: Window,
+        #[command(flatten)]
+        compare: Compare,
+        #[command(flatten)]
+        spec: Spec,
+    },
+    /// Loan offer amount distribution per lender
+    Distribution {
+        #[command(flatten)]
+        window: Window,
+        /// Only users of this partner
+        #[arg(long)]
+        partner: Option<String>,
+        /// Splits each lender by partner
+        #[arg(long)]
+        by_partner: bool,
+        /// Comma separated histogram edges
+        #[arg(
+            long,
+            value_name = "EDGES",
+            value_delimiter = ',',
+            default_value = "10000,25000,50000,100000,200000,500000"
+        )]
+        buckets: Vec<f64>,
+        #[command(flatten)]
+        spec: Spec,
+    },
+    /// Matrix of users with accounts at both of two lenders
+    Overlap {
+        #[command(flatten)]
+        window: Window,
+        /// Only users of this partner
+        #[arg(long)]
+        partner: Option<String>,
+        /// Only counts users approved at both lenders
+        #[arg(long)]
+        approved: bool,
+        #[command(flatten)]
+        spec: Spec,
+    },
+    /// Users, submissions and offers by state and district of their pincode
+    Geo {
+        #[command(flatten)]
+        window: Window,
+        /// Only users of this partner
+        #[arg(long)]
+        partner: Option<String>,
+        #[command(flatten)]
+        spec: Spec,
+    },
+    /// Signups or activity per day, week or month
+    Timeseries {
+        #[command(flatten)]
+        window: Window,
+        /// Date field the buckets are taken from
+        #[arg(long, default_value = "createdAt", value_parser = ["createdAt", "updatedAt"])]
+        field: String,
+        /// Bucket size
+        #[arg(long, value_enum, default_value_t = Unit::Day)]
+        unit: Unit,
+        /// Splits the counts by partner
+        #[arg(long)]
+        by_partner: bool,
+        /// Chart drawn next to the table
+        #[arg(long, value_enum, default_value_t = Chart::Bar)]
+        chart: Chart,
+    },
+    /// Lists the saved reports
+    List {
+        #[command(flatten)]
+        dir: ReportsDir,
+    },
+    /// Runs a saved report by name
+    Run {
+        /// File name of the report without .json
+        name: String,
+        /// Report parameter as key=value, may be repeated
+        #[arg(long = "param", value_name = "KEY=VALUE")]
+        params: Vec<String>,
+        #[command(flatten)]
+        dir: ReportsDir,
+    },
+}
+
+#[derive(Debug, Args)]
+pub struct PipelineArgs {
+    #[command(flatten)]
+    pub window: Window,
+    /// Comma separated columns to sort by, prefix with - for descending
+    #[arg(long, value_name = "KEYS", default_value = "-accounts_no,-createdAt")]
+    pub sort: String,
+    /// Maximum number of rows (defaults to 5 when printing, all when exporting)
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(i64).range(1..))]
+    pub limit: Option<i64>,
+    /// Only users of this partner
+    #[arg(long)]
+    pub partner: Option<String>,
+    /// Only rows with an account at this lender (name or key from the lender spec)
+    #[arg(long)]
+    pub lender: Option<String>,
+    /// Only rows whose --lender status equals this value
+    #[arg(long, requires = "lender")]
+    pub status: Option<String>,
+    /// Writes every row to a file instead of printing a sample
+    #[arg(short, long, value_name = "FILE")]
+    pub output: Option<String>,
+    #[command(flatten)]
+    pub spec: Spec,
+}
+
+#[derive(Debug, Args)]
+pub struct QueryArgs {
+    /// Extended JSON filter
+    #[arg(long, value_name = "JSON")]
+    pub filter: Option<String>,
+    /// Extended JSON projection
+    #[arg(long, value_name = "JSON")]
+    pub projection: Option<String>,
+    /// Extended JSON sort document
+    #[arg(long, value_name = "JSON")]
+    pub sort: Option<String>,
+    /// JSON file with an aggregation pipeline to run instead of a find
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["filter", "projection", "sort"])]
+    pub agg: Option<String>,
+    /// Documents per page (defaults to 20 when printing, all when exporting)
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(i64).range(1..))]
+    pub limit: Option<i64>,
+    /// Page of --limit documents to show, starting at 1
+    #[arg(long, value_name = "N", default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..))]
+    pub page: u64,
+    /// Writes the results to a file instead of printing them
+    #[arg(short, long, value_name = "FILE")]
+    pub output: Option<String>,
+}
+
+/// Report window; each command has its own defaults.
+#[derive(Debug, Args)]
+pub struct Window {
+    /// Start of the report window (RFC 3339)
+    #[arg(long, value_name = "DATE", value_parser = parse_date)]
+    pub from: Option<DateTime>,
+    /// End of the report window, exclusive (RFC 3339)
+    #[arg(long, value_name = "DATE", value_parser = parse_date)]
+    pub to: Option<DateTime>,
+}
+
+impl Window {
+    pub fn or(&self, default_from: &str, default_to: &str) -> (DateTime, DateTime) {
+        let default = |date| DateTime::parse_rfc3339_str(date).expect("default window must be RFC 3339");
+        (
+            self.from.unwrap_or_else(|| default(default_from)),
+            self.to.unwrap_or_else(|| default(default_to)),
+        )
+    }
+
+    pub fn is_given(&self) -> bool {
+        self.from.is_some() || self.to.is_some()
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct Compare {
+    /// Also runs the report for this FROM..TO window and shows the deltas
+    #[arg(long, value_name = "FROM..TO", value_parser = compare::parse_window)]
+    pub compare_to: Option<(DateTime, DateTime)>,
+    /// Percentage change highlighted by --compare-to
+    #[arg(long, value_name = "PERCENT", default_value_t = 10.0)]
+    pub threshold: f64,
+}
+
+#[derive(Debug, Args)]
+pub struct Confirm {
+    /// Skips the typed confirmation, for scripts
+    #[arg(long, short)]
+    pub yes: bool,
+    /// Allows writing to a profile marked protected
+    #[arg(long)]
+    pub allow_protected: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct Spec {
+    /// JSON file describing how to extract each lender from accounts (defaults to the bundled spec)
+    #[arg(long = "lender-spec", value_name = "FILE")]
+    pub path: Option<String>,
+}
+
+#[derive(Debug, Args)]
+pub struct ReportsDir {
+    /// Directory of saved report definitions
+    #[arg(long = "reports-dir", value_name = "DIR", default_value = "reports")]
+    pub path: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    Table,
+    Json,
+    Ndjson,
+    Csv,
+    Yaml,
+    Xlsx,
+}
+
+impl Format {
+    /// The format for printing to stdout, if this one can be printed.
+    pub fn stdout(self) -> Option<output::Format> {
+        match self {
+            Format::Table => Some(output::Format::Table),
+            Format::Json => Some(output::Format::Json),
+            Format::Ndjson => Some(output::Format::Ndjson),
+            Format::Csv => Some(output::Format::Csv),
+            Format::Yaml => Some(output::Format::Yaml),
+            Format::Xlsx => None,
+        }
+    }
+
+    /// The format for writing an --output file, if this one can be written.
+    pub fn file(self) -> Option<export::Format> {
+        match self {
+            Format::Table | Format::Csv => Some(export::Format::Csv),
+            Format::Ndjson => Some(export::Format::Ndjson),
+            Format::Xlsx => Some(export::Format::Xlsx),
+            Format::Json | Format::Yaml => None,
+        }
+    }
+}
+
+fn parse_date(date: &str) -> Result<DateTime, String> {
+    DateTime::parse_rfc3339_str(date).map_err(|e| format!("expected an RFC 3339 date: {}", e))
+}
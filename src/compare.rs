@@ -0,0 +1,90 @@
+use crate::error::Result;
+use crate::output;
+use colored::*;
+use mongodb::bson::{doc, Bson, DateTime, Document};
+
+/// Parses a `FROM..TO` pair of RFC 3339 dates, e.g. `2024-05-08T00:00:00Z..2024-05-15T00:00:00Z`.
+pub fn parse_window(s: &str) -> std::result::Result<(DateTime, DateTime), String> {
+    let (from, to) = s
+        .split_once("..")
+        .ok_or_else(|| format!("expected FROM..TO, got {:?}", s))?;
+    let parse = |date: &str| DateTime::parse_rfc3339_str(date.trim()).map_err(|e| format!("{:?}: {}", date, e));
+    Ok((parse(from)?, parse(to)?))
+}
+
+/// Prints `current` and `previous` side by side with absolute and percentage deltas. Metrics whose
+/// percentage change is at least `threshold` are highlighted, green for growth and red for decline.
+pub fn render(
+    title: &str,
+    current_window: (DateTime, DateTime),
+    previous_window: (DateTime, DateTime),
+    current: &[(String, f64)],
+    previous: &[(String, f64)],
+    threshold: f64,
+) -> Result<()> {
+    let mut labels: Vec<&String> = current.iter().map(|(label, _)| label).collect();
+    for (label, _) in previous {
+        if !labels.contains(&label) {
+            labels.push(label);
+        }
+    }
+    let lookup =
+        |metrics: &[(String, f64)], label: &String| metrics.iter().find(|(l, _)| l == label).map_or(0.0, |(_, v)| *v);
+
+    // (metric, current, previous, delta, percentage change or None when new)
+    let rows: Vec<(&String, f64, f64, f64, Option<f64>)> = labels
+        .into_iter()
+        .map(|label| {
+            let now = lookup(current, label);
+            let before = lookup(previous, label);
+            let delta = now - before;
+            let percent = if before != 0.0 {
+                Some(delta / before.abs() * 100.0)
+            } else if now != 0.0 {
+                None
+            } else {
+                Some(0.0)
+            };
+            (label, now, before, delta, percent)
+        })
+        .collect();
+    let records: Vec<Document> = rows
+        .iter()
+        .map(|(label, now, before, delta, percent)| {
+            doc! {
+                "metric": label.as_str(),
+                "current": now,
+                "previous": before,
+                "delta": delta,
+                "delta_percent": percent.map_or(Bson::Null, Bson::Double),
+            }
+        })
+        .collect();
+
+    output::emit(&records, || {
+        println!("{}", title.bold());
+        println!("  current:  {} to {}", current_window.0, current_window.1);
+        println!("  previous: {} to {}", previous_window.0, previous_window.1);
+        println!(
+            "\n{:<40} {:>14} {:>14} {:>14} {:>9}",
+            "metric", "current", "previous", "delta", "delta %"
+        );
+        for (label, now, before, delta, percent) in &rows {
+            let percent_text = match percent {
+                Some(p) => format!("{:>+8.1}%", p),
+                None => format!("{:>9}", "new"),
+            };
+            let delta_text = format!("{:>+14.2}", delta);
+            let highlighted = percent.is_none_or(|p| p.abs() >= threshold) && *delta != 0.0;
+            let (delta_text, percent_text) = match (highlighted, *delta > 0.0) {
+                (false, _) => (delta_text.normal(), percent_text.normal()),
+                (true, true) => (delta_text.green().bold(), percent_text.green().bold()),
+                (true, false) => (delta_text.red().bold(), percent_text.red().bold()),
+            };
+            println!(
+                "{:<40} {:>14.2} {:>14.2} {} {}",
+                label, now, before, delta_text, percent_text
+            );
+        }
+    })
+}
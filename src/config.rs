@@ -0,0 +1,215 @@
+//! Connection profiles loaded from a JSON config file, e.g.
+//!
+//! ```json
+//! { "profiles": { "prod": { "uri": "mongodb+srv://...", "database": "test", "collection": "users",
+//!                           "read_preference": "secondaryPreferred", "partner": "MoneyTap" } } }
+//! ```
+//!
+//! Without a profile the tool connects to `MONGODB_URI`, database `test`, collection `users`.
+//! Reads go to a secondary when one is available unless the profile or the URI says otherwise;
+//! commands that write always use the primary.
+
+use crate::error::{Error, Result};
+use clap::ValueEnum;
+use mongodb::options::{
+    ClientOptions, ReadConcern, ReadConcernLevel, ReadPreference, ReadPreferenceOptions, SelectionCriteria,
+};
+use mongodb::{bson::Document, Client, Collection};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+const DEFAULT_DATABASE: &str = "test";
+const DEFAULT_COLLECTION: &str = "users";
+
+#[derive(Debug, Default, Deserialize)]
+pub struct Config {
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct Profile {
+    /// Connection string, falls back to `MONGODB_URI`.
+    pub uri: Option<String>,
+    pub database: Option<String>,
+    pub collection: Option<String>,
+    pub read_preference: Option<ReadMode>,
+    pub read_concern: Option<ConcernLevel>,
+    /// Seconds a secondary may lag behind the primary and still be read from, at least 90.
+    pub max_staleness_seconds: Option<u64>,
+    /// Partner used by partner reports when neither `--partner` nor `PARTNER` is given.
+    pub partner: Option<String>,
+    /// Destructive commands also need `--allow-protected`.
+    #[serde(default)]
+    pub protected: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ReadMode {
+    Primary,
+    PrimaryPreferred,
+    Secondary,
+    SecondaryPreferred,
+    Nearest,
+}
+
+impl ReadMode {
+    fn read_preference(self) -> ReadPreference {
+        let options = ReadPreferenceOptions::default();
+        match self {
+            ReadMode::Primary => ReadPreference::Primary,
+            ReadMode::PrimaryPreferred => ReadPreference::PrimaryPreferred { options },
+            ReadMode::Secondary => ReadPreference::Secondary { options },
+            ReadMode::SecondaryPreferred => ReadPreference::SecondaryPreferred { options },
+            ReadMode::Nearest => ReadPreference::Nearest { options },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ConcernLevel {
+    Local,
+    Available,
+    Majority,
+    Linearizable,
+}
+
+impl ConcernLevel {
+    fn read_concern(self) -> ReadConcern {
+        match self {
+            ConcernLevel::Local => ReadConcernLevel::Local,
+            ConcernLevel::Available => ReadConcernLevel::Available,
+            ConcernLevel::Majority => ReadConcernLevel::Majority,
+            ConcernLevel::Linearizable => ReadConcernLevel::Linearizable,
+        }
+        .into()
+    }
+}
+
+/// What a command does with the collection; writes are always sent to the primary.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+impl Config {
+    /// Reads `path`. A missing file is only an error when it was asked for explicitly.
+    pub fn load(path: &str, explicit: bool) -> Result<Config> {
+        if !explicit && !Path::new(path).exists() {
+            return Ok(Config::default());
+        }
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        Ok(serde_json::from_str(&text).map_err(|e| format!("{}: {}", path, e))?)
+    }
+}
+
+/// Where a command reads and writes: the selected profile with `--db`/`--collection` applied.
+#[derive(Debug, Clone)]
+pub struct Target {
+    /// Profile name, `None` when running from `MONGODB_URI` alone.
+    pub profile: Option<String>,
+    pub uri: Option<String>,
+    pub database: String,
+    pub collection: String,
+    pub read_preference: Option<ReadMode>,
+    pub read_concern: Option<ConcernLevel>,
+    pub max_staleness_seconds: Option<u64>,
+    pub partner: Option<String>,
+    pub protected: bool,
+}
+
+impl Target {
+    pub fn resolve(
+        config: &Config,
+        profile: Option<&str>,
+        database: Option<String>,
+        collection: Option<String>,
+    ) -> Result<Target> {
+        let selected = match profile {
+            Some(name) => config.profiles.get(name).cloned().ok_or_else(|| {
+                let known: Vec<&str> = config.profiles.keys().map(String::as_str).collect();
+                format!("unknown profile {:?}; configured profiles: {}", name, known.join(", "))
+            })?,
+            None => Profile::default(),
+        };
+        Ok(Target {
+            profile: profile.map(str::to_string),
+            uri: selected.uri.or_else(|| env::var("MONGODB_URI").ok()),
+            database: database
+                .or(selected.database)
+                .unwrap_or_else(|| DEFAULT_DATABASE.to_string()),
+            collection: collection
+                .or(selected.collection)
+                .unwrap_or_else(|| DEFAULT_COLLECTION.to_string()),
+            read_preference: selected.read_preference,
+            read_concern: selected.read_concern,
+            max_staleness_seconds: selected.max_staleness_seconds,
+            partner: selected.partner,
+            protected: selected.protected,
+        })
+    }
+
+    /// Hosts of the connection string without scheme, credentials or options.
+    pub fn host(&self) -> &str {
+        let Some(uri) = self.uri.as_deref() else {
+            return "?";
+        };
+        let hosts = uri.split_once("://").map_or(uri, |(_, rest)| rest);
+        let hosts = hosts.rsplit_once('@').map_or(hosts, |(_, hosts)| hosts);
+        hosts.split(['/', '?']).next().unwrap_or(hosts)
+    }
+
+    pub async fn connect(&self, access: Access) -> Result<Collection<Document>> {
+        let uri = self.uri.as_deref().ok_or(match &self.profile {
+            Some(profile) => format!("profile {:?} has no uri and MONGODB_URI is not set", profile),
+            None => "MONGODB_URI must be set".to_string(),
+        })?;
+        let mut options = ClientOptions::parse(uri).await.map_err(|e| match Error::from(e) {
+            Error::Validation(message) => Error::Connection(format!("invalid connection string: {}", message)),
+            other => other,
+        })?;
+        let preference = match access {
+            Access::Write => ReadPreference::Primary,
+            Access::Read => self.read_preference(options.selection_criteria.take())?,
+        };
+        tracing::debug!(host = self.host(), ?access, ?preference, "connecting");
+        options.selection_criteria = Some(SelectionCriteria::ReadPreference(preference));
+        // Writes find the users they change on the primary and keep the server's read concern.
+        if let (Access::Read, Some(level)) = (&access, self.read_concern) {
+            options.read_concern = Some(level.read_concern());
+        }
+        let client = Client::with_options(options)?;
+        Ok(client.database(&self.database).collection(&self.collection))
+    }
+
+    /// The profile's read preference, else the URI's, else secondaryPreferred, with
+    /// `max_staleness_seconds` applied.
+    fn read_preference(&self, from_uri: Option<SelectionCriteria>) -> Result<ReadPreference> {
+        let mut preference = match (self.read_preference, from_uri) {
+            (Some(mode), _) => mode.read_preference(),
+            (None, Some(SelectionCriteria::ReadPreference(preference))) => preference,
+            (None, _) => ReadMode::SecondaryPreferred.read_preference(),
+        };
+        if let Some(seconds) = self.max_staleness_seconds {
+            match &mut preference {
+                ReadPreference::Primary => {
+                    return Err(Error::Validation(
+                        "max staleness needs a read preference other than primary".into(),
+                    ))
+                }
+                ReadPreference::PrimaryPreferred { options }
+                | ReadPreference::Secondary { options }
+                | ReadPreference::SecondaryPreferred { options }
+                | ReadPreference::Nearest { options } => options.max_staleness = Some(Duration::from_secs(seconds)),
+            }
+        }
+        Ok(preference)
+    }
+}
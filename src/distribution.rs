@@ -0,0 +1,193 @@
+use crate::chart;
+use crate::error::Result;
+use crate::lenders::{self, LenderSpec};
+use crate::output;
+use crate::query;
+use colored::*;
+use futures_util::stream::TryStreamExt;
+use mongodb::bson::{doc, Bson, DateTime, Document};
+use mongodb::options::AggregateOptions;
+use mongodb::Collection;
+use std::collections::BTreeMap;
+
+const BAR_WIDTH: usize = 30;
+
+/// Prints min, quartiles, p90, max and a histogram of non-zero offer amounts for every lender with
+/// an amount column, optionally split by partner.
+///
+/// Percentiles are computed client side (nearest rank) so the report works on servers without
+/// `$percentile`. `buckets` are the histogram's upper edges in ascending order.
+pub async fn distribution(
+    collection: &Collection<Document>,
+    specs: &[LenderSpec],
+    partner: Option<&str>,
+    by_partner: bool,
+    buckets: &[f64],
+    from: DateTime,
+    to: DateTime,
+) -> Result<()> {
+    let mut filter = doc! { "updatedAt": { "$gte": from, "$lt": to } };
+    if let Some(partner) = partner {
+        filter.insert("partner", partner);
+    }
+    let offers: Vec<Bson> = specs
+        .iter()
+        .filter(|s| s.amount.is_some())
+        .map(|s| {
+            Bson::Document(doc! {
+                "lender": s.name.as_str(),
+                "amount": { "$convert": { "input": format!("${}", s.amount_field()), "to": "double", "onError": null, "onNull": null } },
+            })
+        })
+        .collect();
+
+    let mut pipeline = vec![
+        doc! { "$match": filter },
+        doc! { "$project": { "partner": 1, "accounts": 1 } },
+    ];
+    pipeline.extend(lenders::stages(specs));
+    pipeline.extend([
+        doc! { "$project": { "_id": 0, "partner": 1, "offers": offers } },
+        doc! { "$unwind": "$offers" },
+        doc! { "$match": { "offers.amount": { "$gt": 0 } } },
+        doc! { "$project": { "partner": 1, "lender": "$offers.lender", "amount": "$offers.amount" } },
+    ]);
+    let options = AggregateOptions::builder().batch_size(10_000).build();
+    let mut cursor = query::aggregate(collection, pipeline, options).await?;
+
+    // (lender, partner) -> amounts; an empty partner holds the lender's amounts across all partners
+    let mut groups: BTreeMap<(String, String), Vec<f64>> = BTreeMap::new();
+    while let Some(doc) = cursor.try_next().await? {
+        let lender = doc.get_str("lender")?.to_string();
+        let amount = doc.get_f64("amount")?;
+        if by_partner {
+            let partner = match doc.get("partner") {
+                Some(Bson::String(p)) => p.clone(),
+                _ => "(none)".to_string(),
+            };
+            groups.entry((lender.clone(), partner)).or_default().push(amount);
+        }
+        groups.entry((lender, String::new())).or_default().push(amount);
+    }
+
+    let label = |i: usize| match i {
+        0 => format!("< {}", buckets.first().copied().unwrap_or(f64::INFINITY)),
+        i if i == buckets.len() => format!(">= {}", buckets[i - 1]),
+        i => format!("{} - {}", buckets[i - 1], buckets[i]),
+    };
+    let mut groups: Vec<((String, String), Vec<f64>)> = groups.into_iter().collect();
+    let mut records = Vec::new();
+    for ((lender, partner), amounts) in &mut groups {
+        amounts.sort_by(f64::total_cmp);
+        let histogram: Document = histogram(amounts, buckets)
+            .iter()
+            .enumerate()
+            .map(|(i, count)| (label(i), Bson::Int64(*count as i64)))
+            .collect();
+        records.push(doc! {
+            "lender": lender.as_str(),
+            "partner": if partner.is_empty() { Bson::Null } else { Bson::String(partner.clone()) },
+            "offers": amounts.len() as i64,
+            "min": amounts[0],
+            "p25": percentile(amounts, 25.0),
+            "median": percentile(amounts, 50.0),
+            "p75": percentile(amounts, 75.0),
+            "p90": percentile(amounts, 90.0),
+            "max": amounts[amounts.len() - 1],
+            "histogram": histogram,
+        });
+    }
+
+    output::emit(&records, || {
+        println!("Offer amounts ({} to {})", from, to);
+        if groups.is_empty() {
+            println!("No offers in this window");
+        }
+        for ((lender, partner), amounts) in &groups {
+            let title = if partner.is_empty() {
+                lender.clone()
+            } else {
+                format!("{} / {}", lender, partner)
+            };
+            println!(
+                "\n{} ({} offers)",
+                title.green().bold(),
+                amounts.len().to_string().yellow()
+            );
+            println!(
+                "  min {}  p25 {}  median {}  p75 {}  p90 {}  max {}",
+                amounts[0],
+                percentile(amounts, 25.0),
+                percentile(amounts, 50.0),
+                percentile(amounts, 75.0),
+                percentile(amounts, 90.0),
+                amounts[amounts.len() - 1]
+            );
+
+            let counts = histogram(amounts, buckets);
+            let max = counts.iter().copied().max().unwrap_or(0) as f64;
+            for (i, count) in counts.iter().enumerate() {
+                println!(
+                    "  {:<22} {:>8}  {}",
+                    label(i),
+                    count,
+                    chart::bar(*count as f64, max, BAR_WIDTH)
+                );
+            }
+        }
+    })
+}
+
+/// Nearest-rank percentile of an ascending, non-empty slice.
+fn percentile(sorted: &[f64], p: f64) -> f64 {
+    let rank = ((p / 100.0) * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+/// Counts per bucket: below the first edge, between consecutive edges, and at or above the last.
+fn histogram(amounts: &[f64], edges: &[f64]) -> Vec<usize> {
+    let mut counts = vec![0; edges.len() + 1];
+    for amount in amounts {
+        let bucket = edges.iter().position(|edge| amount < edge).unwrap_or(edges.len());
+        counts[bucket] += 1;
+    }
+    counts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentile_of_one_value() {
+        assert_eq!(percentile(&[5.0], 25.0), 5.0);
+        assert_eq!(percentile(&[5.0], 90.0), 5.0);
+    }
+
+    #[test]
+    fn percentile_of_two_values() {
+        assert_eq!(percentile(&[1.0, 2.0], 25.0), 1.0);
+        assert_eq!(percentile(&[1.0, 2.0], 50.0), 1.0);
+        assert_eq!(percentile(&[1.0, 2.0], 90.0), 2.0);
+    }
+
+    #[test]
+    fn percentile_uses_the_nearest_rank() {
+        let values: Vec<f64> = (1..=10).map(f64::from).collect();
+        assert_eq!(percentile(&values, 25.0), 3.0);
+        assert_eq!(percentile(&values, 50.0), 5.0);
+        assert_eq!(percentile(&values, 90.0), 9.0);
+        assert_eq!(percentile(&values, 100.0), 10.0);
+    }
+
+    #[test]
+    fn histogram_puts_edge_values_in_the_upper_bucket() {
+        assert_eq!(histogram(&[10.0, 20.0], &[10.0, 20.0]), vec![0, 1, 1]);
+    }
+
+    #[test]
+    fn histogram_counts_below_the_first_and_above_the_last_edge() {
+        assert_eq!(histogram(&[1.0, 9.99, 15.0, 20.5, 1e6], &[10.0, 20.0]), vec![2, 1, 2]);
+        assert_eq!(histogram(&[], &[10.0, 20.0]), vec![0, 0, 0]);
+    }
+}
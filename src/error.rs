@@ -0,0 +1,165 @@
+//! The error every command returns. Each kind has its own exit code so scripts can tell a bad
+//! argument from an unreachable cluster or a merge that stopped halfway.
+
+use crate::query::QueryError;
+use mongodb::error::ErrorKind;
+use std::fmt;
+use std::io;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The cluster could not be reached or the connection string is unusable.
+    Connection(String),
+    /// The server rejected the credentials or the user lacks a permission.
+    Auth(String),
+    /// A query or write failed on the server, or returned documents of an unexpected shape.
+    Query(String),
+    /// Bad arguments, filters, spec or config files.
+    Validation(String),
+    /// Some of the work was done before the rest failed.
+    PartialFailure { succeeded: u64, failed: u64, first: String },
+    /// The user declined a confirmation prompt.
+    Aborted,
+    /// Writing output or an export file failed.
+    Io(io::Error),
+}
+
+impl Error {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Io(_) => 1,
+            Error::Validation(_) => 2,
+            Error::Connection(_) => 3,
+            Error::Auth(_) => 4,
+            Error::Query(_) => 5,
+            Error::PartialFailure { .. } => 6,
+            Error::Aborted => 7,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Connection(message) => write!(f, "could not connect to MongoDB: {}", message),
+            Error::Auth(message) => write!(
+                f,
+                "authentication failed: {}; check the credentials in the connection string",
+                message
+            ),
+            Error::Query(message) => write!(f, "query failed: {}", message),
+            Error::Validation(message) => write!(f, "{}", message),
+            Error::PartialFailure {
+                succeeded,
+                failed,
+                first,
+            } => write!(
+                f,
+                "{} succeeded and {} failed before stopping; first failure: {}",
+                succeeded, failed, first
+            ),
+            Error::Aborted => write!(f, "aborted, nothing was changed"),
+            Error::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<mongodb::error::Error> for Error {
+    fn from(e: mongodb::error::Error) -> Self {
+        match e.kind.as_ref() {
+            ErrorKind::Authentication { .. } => Error::Auth(e.to_string()),
+            // Unauthorized and AuthenticationFailed
+            ErrorKind::Command(command) if [13, 18].contains(&command.code) => Error::Auth(command.message.clone()),
+            ErrorKind::ServerSelection { .. }
+            | ErrorKind::DnsResolve { .. }
+            | ErrorKind::Io(_)
+            | ErrorKind::ConnectionPoolCleared { .. }
+            | ErrorKind::InvalidTlsConfig { .. } => Error::Connection(e.to_string()),
+            ErrorKind::InvalidArgument { message, .. } => Error::Validation(message.clone()),
+            _ => Error::Query(e.to_string()),
+        }
+    }
+}
+
+impl From<QueryError> for Error {
+    fn from(e: QueryError) -> Self {
+        match e {
+            QueryError::Mongo(e) => e.into(),
+            too_expensive => Error::Query(too_expensive.to_string()),
+        }
+    }
+}
+
+impl From<mongodb::bson::document::ValueAccessError> for Error {
+    fn from(e: mongodb::bson::document::ValueAccessError) -> Self {
+        Error::Query(format!("unexpected document: {}", e))
+    }
+}
+
+impl From<mongodb::bson::de::Error> for Error {
+    fn from(e: mongodb::bson::de::Error) -> Self {
+        Error::Query(format!("unexpected document: {}", e))
+    }
+}
+
+impl From<mongodb::bson::extjson::de::Error> for Error {
+    fn from(e: mongodb::bson::extjson::de::Error) -> Self {
+        Error::Validation(e.to_string())
+    }
+}
+
+impl From<mongodb::bson::datetime::Error> for Error {
+    fn from(e: mongodb::bson::datetime::Error) -> Self {
+        Error::Validation(e.to_string())
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        if e.is_io() {
+            Error::Io(e.into())
+        } else {
+            Error::Validation(e.to_string())
+        }
+    }
+}
+
+impl From<serde_yaml::Error> for Error {
+    fn from(e: serde_yaml::Error) -> Self {
+        Error::Io(io::Error::other(e))
+    }
+}
+
+impl From<csv::Error> for Error {
+    fn from(e: csv::Error) -> Self {
+        Error::Io(e.into())
+    }
+}
+
+impl From<rust_xlsxwriter::XlsxError> for Error {
+    fn from(e: rust_xlsxwriter::XlsxError) -> Self {
+        Error::Io(io::Error::other(e))
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<String> for Error {
+    fn from(message: String) -> Self {
+        Error::Validation(message)
+    }
+}
+
+impl From<&str> for Error {
+    fn from(message: &str) -> Self {
+        Error::Validation(message.to_string())
+    }
+}
@@ -0,0 +1,154 @@
+use crate::analytics::{self, PipelineQuery};
+use crate::error::Result;
+use crate::lenders::LenderSpec;
+use crate::query;
+use crate::redact;
+use futures_util::stream::{Stream, TryStreamExt};
+use mongodb::bson::{Bson, DateTime, Document};
+use mongodb::options::AggregateOptions;
+use mongodb::Collection;
+use rust_xlsxwriter::Workbook;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Csv,
+    Ndjson,
+    Xlsx,
+}
+
+/// Streams every flattened pipeline row matching `query` to `path`, returning the number of rows written.
+pub async fn pipeline(
+    collection: &Collection<Document>,
+    specs: &[LenderSpec],
+    from: DateTime,
+    to: DateTime,
+    query: &PipelineQuery,
+    path: &str,
+    format: Format,
+) -> Result<u64> {
+    let columns = analytics::pipeline_columns(specs);
+    let pipeline = analytics::pipeline_stages(specs, from, to, query);
+    let options = AggregateOptions::builder().allow_disk_use(true).build();
+    let cursor = query::aggregate(collection, pipeline, options).await?;
+    write(cursor, &columns, path, format).await
+}
+
+/// Streams `rows` to `path` with one column per entry of `columns`, returning the number of rows written.
+/// NDJSON with no `columns` writes every field of each document.
+pub async fn write<S>(mut rows: S, columns: &[String], path: &str, format: Format) -> Result<u64>
+where
+    S: Stream<Item = mongodb::error::Result<Document>> + Unpin,
+{
+    let mut written = 0u64;
+
+    match format {
+        Format::Csv => {
+            let mut writer = csv::Writer::from_path(path)?;
+            writer.write_record(columns)?;
+            while let Some(doc) = rows.try_next().await? {
+                let doc = redact::document(&doc);
+                writer.write_record(columns.iter().map(|c| cell(lookup(&doc, c))))?;
+                written += 1;
+            }
+            writer.flush()?;
+        }
+        Format::Ndjson => {
+            let mut writer = BufWriter::new(File::create(path)?);
+            while let Some(doc) = rows.try_next().await? {
+                let doc = redact::document(&doc);
+                let fields: Vec<&String> = if columns.is_empty() {
+                    doc.keys().collect()
+                } else {
+                    columns.iter().collect()
+                };
+                let mut row = Document::new();
+                for column in fields {
+                    let value = match lookup(&doc, column) {
+                        Some(Bson::DateTime(date)) => Bson::String(date.try_to_rfc3339_string()?),
+                        Some(value) => value.clone(),
+                        None => Bson::Null,
+                    };
+                    row.insert(column.as_str(), value);
+                }
+                serde_json::to_writer(&mut writer, &row)?;
+                writer.write_all(b"\n")?;
+                written += 1;
+            }
+            writer.flush()?;
+        }
+        Format::Xlsx => {
+            let mut workbook = Workbook::new();
+            let sheet = workbook.add_worksheet();
+            for (i, column) in columns.iter().enumerate() {
+                sheet.write_string(0, i as u16, column)?;
+            }
+            while let Some(doc) = rows.try_next().await? {
+                let doc = redact::document(&doc);
+                let row = u32::try_from(written + 1).map_err(|_| "too many rows for an xlsx sheet")?;
+                for (i, column) in columns.iter().enumerate() {
+                    match lookup(&doc, column) {
+                        Some(Bson::Int32(n)) => sheet.write_number(row, i as u16, *n)?,
+                        Some(Bson::Int64(n)) => sheet.write_number(row, i as u16, *n as f64)?,
+                        Some(Bson::Double(n)) => sheet.write_number(row, i as u16, *n)?,
+                        value => sheet.write_string(row, i as u16, cell(value))?,
+                    };
+                }
+                written += 1;
+            }
+            workbook.save(path)?;
+        }
+    }
+    Ok(written)
+}
+
+/// The value at a dotted `path` such as `address.city`, which is how projections and report
+/// columns name nested fields. A key that itself contains the dots wins.
+pub fn lookup<'a>(doc: &'a Document, path: &str) -> Option<&'a Bson> {
+    if let Some(value) = doc.get(path) {
+        return Some(value);
+    }
+    let mut parts = path.split('.');
+    let mut value = doc.get(parts.next()?)?;
+    for part in parts {
+        value = value.as_document()?.get(part)?;
+    }
+    Some(value)
+}
+
+/// Renders a value as a single spreadsheet cell: dates as RFC 3339, nested values as JSON.
+pub fn cell(value: Option<&Bson>) -> String {
+    match value {
+        None | Some(Bson::Null) => String::new(),
+        Some(Bson::String(s)) => s.clone(),
+        Some(Bson::DateTime(date)) => date.try_to_rfc3339_string().unwrap_or_else(|_| date.to_string()),
+        Some(Bson::Int32(n)) => n.to_string(),
+        Some(Bson::Int64(n)) => n.to_string(),
+        Some(Bson::Double(n)) => n.to_string(),
+        Some(Bson::Boolean(b)) => b.to_string(),
+        Some(Bson::ObjectId(id)) => id.to_hex(),
+        Some(other) => other.clone().into_relaxed_extjson().to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mongodb::bson::doc;
+
+    #[test]
+    fn lookup_follows_dotted_paths() {
+        let doc = doc! { "address": { "city": "Pune", "geo": { "pincode": "411001" } } };
+        assert_eq!(lookup(&doc, "address.city"), Some(&Bson::from("Pune")));
+        assert_eq!(lookup(&doc, "address.geo.pincode"), Some(&Bson::from("411001")));
+        assert_eq!(lookup(&doc, "address.state"), None);
+        assert_eq!(lookup(&doc, "address.city.name"), None);
+    }
+
+    #[test]
+    fn lookup_prefers_a_key_with_dots() {
+        let doc = doc! { "address.city": "flat", "address": { "city": "nested" } };
+        assert_eq!(lookup(&doc, "address.city"), Some(&Bson::from("flat")));
+    }
+}
@@ -0,0 +1,283 @@
+use crate::analytics::as_f64;
+use crate::error::Result;
+use crate::lenders::{self, LenderSpec};
+use crate::output;
+use crate::query;
+use colored::*;
+use futures_util::stream::TryStreamExt;
+use mongodb::bson::{doc, Bson, DateTime, Document};
+use mongodb::Collection;
+use std::collections::HashMap;
+
+/// Offline pincode directory shipped with the binary.
+///
+/// Rows are keyed by the three digit sorting district prefix. Most prefixes span several revenue
+/// districts, so their district is blank and their users are only attributed to a state. Six digit
+/// rows override the prefix for the union territory enclaves that share a sorting district with a
+/// neighbouring state: Puducherry, Karaikal, Mahe and Yanam, Lakshadweep, and Dadra and Nagar
+/// Haveli and Daman and Diu. This is not an office-level directory.
+const DIRECTORY: &str = include_str!("../data/pincodes.csv");
+
+/// District of users whose sorting district spans several districts.
+const UNRESOLVED_DISTRICT: &str = "(several districts)";
+
+/// How many unknown or invalid pincodes to list individually.
+const LISTED_PINCODES: usize = 20;
+
+struct Place {
+    district: Option<String>,
+    state: String,
+}
+
+struct Directory {
+    places: HashMap<String, Place>,
+}
+
+impl Directory {
+    fn bundled() -> Result<Self> {
+        let mut reader = csv::Reader::from_reader(DIRECTORY.as_bytes());
+        let mut places = HashMap::new();
+        for record in reader.records() {
+            let record = record?;
+            let district = record.get(1).filter(|d| !d.is_empty()).map(str::to_string);
+            let state = record
+                .get(2)
+                .ok_or("pincode directory row without a state")?
+                .to_string();
+            places.insert(record[0].to_string(), Place { district, state });
+        }
+        Ok(Directory { places })
+    }
+
+    /// Most specific match for a valid six digit pincode.
+    fn lookup(&self, pincode: &str) -> Option<&Place> {
+        self.places.get(pincode).or_else(|| self.places.get(&pincode[..3]))
+    }
+}
+
+#[derive(Default, Clone, Copy)]
+struct Totals {
+    users: f64,
+    submissions: f64,
+    offers: f64,
+}
+
+impl Totals {
+    fn add(&mut self, other: Totals) {
+        self.users += other.users;
+        self.submissions += other.submissions;
+        self.offers += other.offers;
+    }
+}
+
+/// Aggregates users, lender submissions and offers by state and district of their pincode.
+pub async fn geo(
+    collection: &Collection<Document>,
+    specs: &[LenderSpec],
+    partner: Option<&str>,
+    from: DateTime,
+    to: DateTime,
+) -> Result<()> {
+    let directory = Directory::bundled()?;
+
+    let mut filter = doc! { "updatedAt": { "$gte": from, "$lt": to } };
+    if let Some(partner) = partner {
+        filter.insert("partner", partner);
+    }
+    let amounts: Vec<Bson> = specs
+        .iter()
+        .filter(|s| s.amount.is_some())
+        .map(|s| Bson::String(format!("${}", s.amount_field())))
+        .collect();
+    // Amounts may be stored as strings, so compare them as numbers like the other reports do.
+    let amount = doc! { "$convert": { "input": "$$this", "to": "double", "onError": null, "onNull": null } };
+    let offered = doc! { "$gt": [amount, 0] };
+
+    let mut pipeline = vec![
+        doc! { "$match": filter },
+        doc! { "$project": { "pincode": 1, "accounts": 1 } },
+    ];
+    pipeline.extend(lenders::stages(specs));
+    pipeline.push(doc! { "$group": {
+        "_id": { "$convert": { "input": "$pincode", "to": "string", "onError": null, "onNull": null } },
+        "users": { "$sum": 1 },
+        "submissions": { "$sum": "$accounts_no" },
+        "offers": { "$sum": { "$size": { "$filter": { "input": amounts, "cond": offered } } } },
+    } });
+    let mut cursor = query::aggregate(collection, pipeline, None).await?;
+
+    let mut states: HashMap<String, Totals> = HashMap::new();
+    let mut districts: HashMap<(String, String), Totals> = HashMap::new();
+    let mut unknown: Vec<(String, Totals)> = Vec::new();
+    let mut invalid: Vec<(String, Totals)> = Vec::new();
+
+    while let Some(doc) = cursor.try_next().await? {
+        let totals = Totals {
+            users: as_f64(doc.get("users")),
+            submissions: as_f64(doc.get("submissions")),
+            offers: as_f64(doc.get("offers")),
+        };
+        let pincode = match doc.get("_id") {
+            Some(Bson::String(p)) => p.trim().to_string(),
+            _ => {
+                invalid.push(("(missing)".to_string(), totals));
+                continue;
+            }
+        };
+        if !is_valid(&pincode) {
+            invalid.push((pincode, totals));
+            continue;
+        }
+        match directory.lookup(&pincode) {
+            Some(place) => {
+                states.entry(place.state.clone()).or_default().add(totals);
+                let district = place
+                    .district
+                    .clone()
+                    .unwrap_or_else(|| UNRESOLVED_DISTRICT.to_string());
+                districts
+                    .entry((place.state.clone(), district))
+                    .or_default()
+                    .add(totals);
+            }
+            None => unknown.push((pincode, totals)),
+        }
+    }
+
+    let mut states: Vec<(String, Totals)> = states.into_iter().collect();
+    states.sort_by(|a, b| b.1.users.total_cmp(&a.1.users));
+    let mut districts: Vec<((String, String), Totals)> = districts.into_iter().collect();
+    districts.sort_by(|a, b| b.1.users.total_cmp(&a.1.users));
+    unknown.sort_by(|a, b| b.1.users.total_cmp(&a.1.users));
+    invalid.sort_by(|a, b| b.1.users.total_cmp(&a.1.users));
+
+    let record = |level: &str, state: Option<&str>, district: Option<&str>, pincode: Option<&str>, totals: &Totals| {
+        doc! {
+            "level": level,
+            "state": state,
+            "district": district,
+            "pincode": pincode,
+            "users": totals.users,
+            "submissions": totals.submissions,
+            "offers": totals.offers,
+        }
+    };
+    let mut records = Vec::new();
+    for (state, totals) in &states {
+        records.push(record("state", Some(state), None, None, totals));
+    }
+    for ((state, district), totals) in &districts {
+        records.push(record("district", Some(state), Some(district), None, totals));
+    }
+    for (pincode, totals) in &unknown {
+        records.push(record("unknown", None, None, Some(pincode), totals));
+    }
+    for (pincode, totals) in &invalid {
+        records.push(record("invalid", None, None, Some(pincode), totals));
+    }
+
+    output::emit(&records, || {
+        println!("{}", "By state".bold());
+        print_header("state");
+        for (state, totals) in &states {
+            print_row(state, totals);
+        }
+
+        println!("\n{}", "By district".bold());
+        print_header("district");
+        for ((state, district), totals) in &districts {
+            print_row(&format!("{}, {}", district, state), totals);
+        }
+        let located: f64 = states.iter().map(|(_, t)| t.users).sum();
+        let unresolved: f64 = districts
+            .iter()
+            .filter(|((_, district), _)| district == UNRESOLVED_DISTRICT)
+            .map(|(_, t)| t.users)
+            .sum();
+        if unresolved > 0.0 {
+            println!(
+                "{}",
+                format!(
+                    "{} of {} users ({:.0}%) are in sorting districts the bundled directory cannot split by district",
+                    unresolved,
+                    located,
+                    unresolved / located * 100.0
+                )
+                .bright_black()
+            );
+        }
+
+        print_pincodes("Unknown pincodes", &unknown);
+        print_pincodes("Invalid pincodes", &invalid);
+    })
+}
+
+fn is_valid(pincode: &str) -> bool {
+    pincode.len() == 6 && pincode.bytes().all(|b| b.is_ascii_digit()) && !pincode.starts_with('0')
+}
+
+fn print_header(label: &str) {
+    println!("{:<40} {:>10} {:>12} {:>10}", label, "users", "submissions", "offers");
+}
+
+fn print_row(label: &str, totals: &Totals) {
+    println!(
+        "{:<40} {:>10} {:>12} {:>10}",
+        label.green(),
+        totals.users.to_string().yellow(),
+        totals.submissions,
+        totals.offers
+    );
+}
+
+/// Prints the busiest of `pincodes`, which are sorted by users.
+fn print_pincodes(title: &str, pincodes: &[(String, Totals)]) {
+    let users: f64 = pincodes.iter().map(|(_, t)| t.users).sum();
+    println!("\n{} ({} distinct, {} users)", title.bold(), pincodes.len(), users);
+    for (pincode, totals) in pincodes.iter().take(LISTED_PINCODES) {
+        print_row(&format!("{:?}", pincode), totals);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn place(directory: &Directory, pincode: &str) -> (Option<String>, String) {
+        let place = directory.lookup(pincode).unwrap();
+        (place.district.clone(), place.state.clone())
+    }
+
+    #[test]
+    fn bundled_directory_has_no_duplicate_rows() {
+        let mut reader = csv::Reader::from_reader(DIRECTORY.as_bytes());
+        let mut seen = std::collections::HashSet::new();
+        for record in reader.records() {
+            let record = record.unwrap();
+            assert!(seen.insert(record[0].to_string()), "duplicate row {}", &record[0]);
+        }
+    }
+
+    #[test]
+    fn union_territory_enclaves_override_their_prefix() {
+        let directory = Directory::bundled().unwrap();
+        assert_eq!(
+            place(&directory, "605001"),
+            (Some("Puducherry".into()), "Puducherry".into())
+        );
+        assert_eq!(
+            place(&directory, "609602"),
+            (Some("Karaikal".into()), "Puducherry".into())
+        );
+        assert_eq!(
+            place(&directory, "682555"),
+            (Some("Lakshadweep".into()), "Lakshadweep".into())
+        );
+        assert_eq!(place(&directory, "682001"), (Some("Ernakulam".into()), "Kerala".into()));
+        assert_eq!(
+            place(&directory, "396210").1,
+            "Dadra and Nagar Haveli and Daman and Diu"
+        );
+        assert_eq!(place(&directory, "605602").1, "Tamil Nadu");
+    }
+}
@@ -0,0 +1,170 @@
+use crate::analytics::as_f64;
+use crate::error::Result;
+use crate::output;
+use colored::*;
+use futures_util::stream::TryStreamExt;
+use mongodb::bson::{doc, Bson, Document};
+use mongodb::{Collection, IndexModel};
+
+/// Indexes backing the queries this tool issues, with the commands that need them.
+const SUGGESTED: &[(&[&str], &str)] = &[
+    (
+        &["updatedAt"],
+        "updatedAt windows in duplicates, merge, pipeline and every report",
+    ),
+    (&["phone"], "per-phone lookups during merge"),
+    (&["partner", "partnerSent", "isBanned"], "total counts"),
+    (&["partner", "updatedAt"], "partner filtered reports"),
+    (&["createdAt"], "time series on createdAt"),
+];
+
+struct Existing {
+    name: String,
+    keys: Document,
+    size: f64,
+    ops: f64,
+    since: String,
+}
+
+/// Lists the collection's indexes with size and usage, checks them against [`SUGGESTED`] and, with
+/// `apply`, creates the missing ones.
+pub async fn indexes(collection: &Collection<Document>, apply: bool) -> Result<()> {
+    // Metadata stages must come first in the pipeline, so these bypass the query guard.
+    let stats = collection
+        .aggregate(vec![doc! { "$collStats": { "storageStats": {} } }], None)
+        .await?
+        .try_next()
+        .await?
+        .unwrap_or_default();
+    let sizes = stats
+        .get_document("storageStats")
+        .and_then(|s| s.get_document("indexSizes"))
+        .cloned()
+        .unwrap_or_default();
+    let usage: Vec<Document> = collection
+        .aggregate(vec![doc! { "$indexStats": {} }], None)
+        .await?
+        .try_collect()
+        .await?;
+
+    let mut existing: Vec<Existing> = usage
+        .iter()
+        .map(|index| {
+            let name = index.get_str("name").unwrap_or("?").to_string();
+            let accesses = index.get_document("accesses").ok();
+            Existing {
+                size: as_f64(sizes.get(&name)),
+                keys: index.get_document("key").cloned().unwrap_or_default(),
+                ops: as_f64(accesses.and_then(|a| a.get("ops"))),
+                since: match accesses.and_then(|a| a.get("since")) {
+                    Some(Bson::DateTime(d)) => d.try_to_rfc3339_string().unwrap_or_default(),
+                    _ => String::new(),
+                },
+                name,
+            }
+        })
+        .collect();
+    existing.sort_by(|a, b| a.name.cmp(&b.name));
+
+    // (suggested keys, reason, covering index)
+    let suggestions: Vec<(Document, &str, Option<&Existing>)> = SUGGESTED
+        .iter()
+        .map(|(fields, reason)| {
+            let mut keys = Document::new();
+            for field in *fields {
+                keys.insert(*field, 1);
+            }
+            let index = existing.iter().find(|index| covers(&index.keys, fields));
+            (keys, *reason, index)
+        })
+        .collect();
+
+    let mut records: Vec<Document> = existing
+        .iter()
+        .map(|index| {
+            doc! {
+                "kind": "index",
+                "name": &index.name,
+                "keys": index.keys.clone(),
+                "size": index.size,
+                "ops": index.ops,
+                "since": &index.since,
+            }
+        })
+        .collect();
+    for (keys, reason, index) in &suggestions {
+        records.push(doc! {
+            "kind": if index.is_some() { "suggested" } else { "missing" },
+            "name": index.map(|i| i.name.as_str()),
+            "keys": keys.clone(),
+            "reason": *reason,
+        });
+    }
+    let missing: Vec<&Document> = suggestions
+        .iter()
+        .filter(|(_, _, index)| index.is_none())
+        .map(|(keys, _, _)| keys)
+        .collect();
+
+    output::emit(&records, || {
+        println!("{}", format!("Indexes on {}", collection.name()).bold());
+        println!("  {:<36} {:<44} {:>10} {:>12}  since", "name", "keys", "size", "ops");
+        for index in &existing {
+            println!(
+                "  {:<36} {:<44} {:>10} {:>12}  {}",
+                index.name.green(),
+                index.keys.to_string(),
+                human_size(index.size),
+                if index.ops > 0.0 {
+                    index.ops.to_string().yellow()
+                } else {
+                    "0".red()
+                },
+                index.since.bright_black()
+            );
+        }
+
+        println!("\n{}", "Suggested for this tool's queries".bold());
+        for (keys, reason, index) in &suggestions {
+            match index {
+                Some(index) => println!(
+                    "  {} {:<44} {}",
+                    "ok".green(),
+                    keys.to_string(),
+                    index.name.bright_black()
+                ),
+                None => println!("  {} {:<44} {}", "missing".red(), keys.to_string(), reason),
+            }
+        }
+        if !missing.is_empty() && !apply {
+            println!("\nRerun with --apply to create {} missing index(es)", missing.len());
+        }
+    })?;
+
+    if !apply {
+        return Ok(());
+    }
+    for keys in missing {
+        let created = collection
+            .create_index(IndexModel::builder().keys(keys.clone()).build(), None)
+            .await?;
+        tracing::info!(index = %created.index_name, keys = %keys, "index created");
+    }
+    Ok(())
+}
+
+/// Whether an index with `keys` can serve queries on `fields`, i.e. `fields` is a prefix of its keys.
+fn covers(keys: &Document, fields: &[&str]) -> bool {
+    keys.len() >= fields.len() && keys.keys().zip(fields).all(|(key, field)| key == field)
+}
+
+fn human_size(bytes: f64) -> String {
+    const UNITS: [&str; 4] = ["B", "KB", "MB", "GB"];
+    let mut size = bytes;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
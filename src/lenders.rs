@@ -0,0 +1,293 @@
+use crate::error::Result;
+use mongodb::bson::{doc, Bson, Document};
+use serde::Deserialize;
+use std::fs;
+
+/// Spec shipped with the binary, used when no `--lender-spec` file is given.
+const DEFAULT_SPEC: &str = include_str!("../lenders.json");
+
+/// How one lender's account inside `accounts` maps onto flat report columns.
+#[derive(Debug, Clone, Deserialize)]
+pub struct LenderSpec {
+    /// Value of `accounts.name` for this lender.
+    pub name: String,
+    /// Column prefix, e.g. `fibe` for `fibe_status`, `fibe_id` and `fibe_loanAmount`.
+    pub key: String,
+    pub status: Option<FieldSpec>,
+    pub id: Option<FieldSpec>,
+    pub amount: Option<FieldSpec>,
+    /// Status values that count as an approval even without a loan amount.
+    #[serde(default)]
+    pub approved: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct FieldSpec {
+    /// Dotted path inside the lender's account document.
+    pub path: String,
+    /// The path resolves to an array; take its first element.
+    #[serde(default)]
+    pub first: bool,
+    /// Report a true boolean as `"success"`; `false` leaves the column out like a missing field.
+    #[serde(default)]
+    pub boolean: bool,
+    /// Values treated like a missing field, e.g. `0` or the string `"null"`.
+    #[serde(default)]
+    pub empty: Vec<Bson>,
+}
+
+impl LenderSpec {
+    pub fn details_field(&self) -> String {
+        format!("{}_details", self.key)
+    }
+
+    pub fn status_field(&self) -> String {
+        format!("{}_status", self.key)
+    }
+
+    pub fn id_field(&self) -> String {
+        format!("{}_id", self.key)
+    }
+
+    pub fn amount_field(&self) -> String {
+        format!("{}_loanAmount", self.key)
+    }
+
+    /// Whether [`approved`] can tell approvals apart: the lender has a loan amount or `approved`
+    /// statuses.
+    pub fn reports_approvals(&self) -> bool {
+        self.amount.is_some() || (self.status.is_some() && !self.approved.is_empty())
+    }
+
+    /// Flat columns this lender contributes to a pipeline row, in a stable order.
+    pub fn columns(&self) -> Vec<String> {
+        let mut columns = Vec::new();
+        if self.status.is_some() {
+            columns.push(self.status_field());
+        }
+        if self.id.is_some() {
+            columns.push(self.id_field());
+        }
+        if self.amount.is_some() {
+            columns.push(self.amount_field());
+        }
+        columns
+    }
+}
+
+impl FieldSpec {
+    /// `$cond` yielding the field's value, or `$$REMOVE` when it is missing or one of the `empty` values.
+    fn expression(&self, details: &str) -> Bson {
+        let path = Bson::String(format!("${}.{}", details, self.path));
+        let value = if self.first {
+            Bson::Document(doc! { "$arrayElemAt": [path, 0] })
+        } else {
+            path
+        };
+
+        // A boolean is tested for truthiness, so `false` is dropped along with a missing value.
+        let mut checks = if self.boolean {
+            vec![Bson::Document(doc! { "$ifNull": [value.clone(), null] })]
+        } else {
+            vec![Bson::Document(doc! { "$gt": [value.clone(), null] })]
+        };
+        for empty in &self.empty {
+            checks.push(Bson::Document(doc! { "$ne": [value.clone(), empty.clone()] }));
+        }
+        let present = if checks.len() == 1 {
+            checks.remove(0)
+        } else {
+            Bson::Document(doc! { "$and": checks })
+        };
+
+        let then = if self.boolean { Bson::from("success") } else { value };
+
+        Bson::Document(doc! {
+            "$cond": {
+                "if": present,
+                "then": then,
+                "else": "$$REMOVE",
+            }
+        })
+    }
+}
+
+/// Expression that is true when the user has an account at this lender, evaluated after [`stages`].
+pub fn submitted(spec: &LenderSpec) -> Bson {
+    Bson::Document(doc! { "$gt": [format!("${}", spec.details_field()), null] })
+}
+
+/// Expression that is true when the lender made a non-zero offer or reported one of its `approved`
+/// statuses, evaluated after [`stages`]. Always false unless [`LenderSpec::reports_approvals`].
+pub fn approved(spec: &LenderSpec) -> Bson {
+    let mut checks = Vec::new();
+    if spec.amount.is_some() {
+        let amount = doc! { "$convert": { "input": format!("${}", spec.amount_field()), "to": "double", "onError": null, "onNull": null } };
+        checks.push(Bson::Document(doc! { "$gt": [amount, 0] }));
+    }
+    if spec.status.is_some() && !spec.approved.is_empty() {
+        checks.push(Bson::Document(
+            doc! { "$in": [format!("${}", spec.status_field()), spec.approved.clone()] },
+        ));
+    }
+    Bson::Document(doc! { "$or": checks })
+}
+
+/// Loads lender specs from `path`, or the bundled `lenders.json` when no path is given.
+pub fn load(path: Option<&str>) -> Result<Vec<LenderSpec>> {
+    let specs: Vec<LenderSpec> = match path {
+        Some(path) => serde_json::from_str(&fs::read_to_string(path)?)?,
+        None => serde_json::from_str(DEFAULT_SPEC)?,
+    };
+    for (i, spec) in specs.iter().enumerate() {
+        if spec.key.is_empty() || spec.key.contains(['.', '$']) {
+            return Err(format!("lender {:?} has an invalid key {:?}", spec.name, spec.key).into());
+        }
+        if let Some(other) = specs[..i].iter().find(|other| other.key == spec.key) {
+            return Err(format!(
+                "lenders {:?} and {:?} share the key {:?}",
+                other.name, spec.name, spec.key
+            )
+            .into());
+        }
+    }
+    Ok(specs)
+}
+
+/// Stages that pick each lender's account out of `accounts` into `<key>_details`, then flatten its
+/// status, id and loan amount into `<key>_status`, `<key>_id` and `<key>_loanAmount`.
+pub fn stages(specs: &[LenderSpec]) -> Vec<Document> {
+    let mut details = doc! {
+        "accounts_no": {
+            "$cond": {
+                "if": { "$isArray": "$accounts" },
+                "then": { "$size": "$accounts" },
+                "else": 0,
+            },
+        },
+    };
+    let mut flattened = Document::new();
+
+    for spec in specs {
+        details.insert(
+            spec.details_field(),
+            doc! {
+                "$arrayElemAt": [
+                    {
+                        "$filter": {
+                            "input": "$accounts",
+                            "as": "account",
+                            "cond": { "$eq": ["$$account.name", spec.name.as_str()] },
+                        },
+                    },
+                    0,
+                ],
+            },
+        );
+
+        let fields = [
+            (spec.status_field(), &spec.status),
+            (spec.id_field(), &spec.id),
+            (spec.amount_field(), &spec.amount),
+        ];
+        for (column, field) in fields {
+            if let Some(field) = field {
+                flattened.insert(column, field.expression(&spec.details_field()));
+            }
+        }
+    }
+
+    vec![doc! { "$addFields": details }, doc! { "$addFields": flattened }]
+}
+
+/// `$project` stage dropping `accounts` and every `<key>_details` helper field.
+pub fn cleanup_stage(specs: &[LenderSpec]) -> Document {
+    let mut projection = doc! { "_id": 0, "accounts": 0 };
+    for spec in specs {
+        projection.insert(spec.details_field(), 0);
+    }
+    doc! { "$project": projection }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The generated expression of one flattened column for the bundled spec.
+    fn flattened(column: &str) -> Document {
+        let stages = stages(&load(None).unwrap());
+        stages[1]
+            .get_document("$addFields")
+            .unwrap()
+            .get_document(column)
+            .unwrap()
+            .clone()
+    }
+
+    #[test]
+    fn plain_fields_need_a_value() {
+        assert_eq!(
+            flattened("cashe_status"),
+            doc! { "$cond": {
+                "if": { "$gt": ["$cashe_details.status", null] },
+                "then": "$cashe_details.status",
+                "else": "$$REMOVE",
+            } }
+        );
+    }
+
+    #[test]
+    fn empty_values_are_dropped() {
+        assert_eq!(
+            flattened("fibe_id"),
+            doc! { "$cond": {
+                "if": { "$and": [
+                    { "$gt": ["$fibe_details.id", null] },
+                    { "$ne": ["$fibe_details.id", "null"] },
+                ] },
+                "then": "$fibe_details.id",
+                "else": "$$REMOVE",
+            } }
+        );
+    }
+
+    #[test]
+    fn first_takes_the_first_element() {
+        let amount = doc! { "$arrayElemAt": ["$payme_details.limit.credit_limit", 0] };
+        assert_eq!(
+            flattened("payme_loanAmount"),
+            doc! { "$cond": {
+                "if": { "$and": [
+                    { "$gt": [amount.clone(), null] },
+                    { "$ne": [amount.clone(), 0] },
+                ] },
+                "then": amount,
+                "else": "$$REMOVE",
+            } }
+        );
+    }
+
+    #[test]
+    fn booleans_report_success_and_drop_false() {
+        assert_eq!(
+            flattened("upwards_marketplace_status"),
+            doc! { "$cond": {
+                "if": { "$ifNull": ["$upwards_marketplace_details.data.is_success", null] },
+                "then": "success",
+                "else": "$$REMOVE",
+            } }
+        );
+    }
+
+    #[test]
+    fn missing_fields_contribute_no_column() {
+        let spec: LenderSpec =
+            serde_json::from_str(r#"{ "name": "Acme", "key": "acme", "id": { "path": "id" } }"#).unwrap();
+        let stages = stages(std::slice::from_ref(&spec));
+        let flattened = stages[1].get_document("$addFields").unwrap();
+        assert!(!flattened.contains_key("acme_status"));
+        assert!(!flattened.contains_key("acme_loanAmount"));
+        assert!(flattened.contains_key("acme_id"));
+        assert_eq!(spec.columns(), vec!["acme_id"]);
+    }
+}
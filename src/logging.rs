@@ -0,0 +1,51 @@
+//! Diagnostics go through `tracing` to stderr or `--log-file`, as text or one JSON object per line.
+//! Command results stay on stdout.
+
+use crate::error::Result;
+use clap::ValueEnum;
+use std::fs::OpenOptions;
+use std::io;
+use std::sync::Mutex;
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::prelude::*;
+
+/// Target of the records of what a merge wrote. They are kept at info even under `-q`.
+pub const MERGE: &str = "merge";
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+/// Installs the global subscriber. `verbosity` is the number of `-v` minus the number of `-q`:
+/// info by default, debug and trace above it, warn and error below.
+pub fn init(verbosity: i8, format: LogFormat, file: Option<&str>) -> Result<()> {
+    let level = match verbosity {
+        i8::MIN..=-2 => LevelFilter::ERROR,
+        -1 => LevelFilter::WARN,
+        0 => LevelFilter::INFO,
+        1 => LevelFilter::DEBUG,
+        _ => LevelFilter::TRACE,
+    };
+    let filter = Targets::new()
+        .with_default(level)
+        .with_target(MERGE, level.max(LevelFilter::INFO));
+    let layer = tracing_subscriber::fmt::layer().with_target(false);
+    let layer = match (format, file) {
+        (LogFormat::Text, None) => layer.with_writer(io::stderr).boxed(),
+        (LogFormat::Json, None) => layer.json().with_writer(io::stderr).boxed(),
+        (format, Some(path)) => {
+            let file = Mutex::new(OpenOptions::new().create(true).append(true).open(path)?);
+            let layer = layer.with_ansi(false).with_writer(file);
+            match format {
+                LogFormat::Text => layer.boxed(),
+                LogFormat::Json => layer.json().boxed(),
+            }
+        }
+    };
+    tracing_subscriber::registry().with(layer.with_filter(filter)).init();
+    Ok(())
+}
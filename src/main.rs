@@ -1,76 +1,331 @@
-use clap::{Arg, Command};
-use mongodb::{bson::Document, Client};
+use clap::Parser;
+use cli::{Cli, Commands, Report};
+use colored::*;
+use config::Access;
+use error::{Error, Result};
+use mongodb::{bson::Document, Collection};
 use std::env;
-use tokio;
 
+mod adhoc;
 mod analytics;
+mod audit;
+mod chart;
+mod cli;
+mod compare;
+mod config;
+mod distribution;
+mod error;
+mod export;
+mod geo;
+mod indexes;
+mod lenders;
+mod logging;
 mod merge2;
 mod merge_users;
+mod output;
+mod overlap;
+mod query;
+mod redact;
+mod render;
+mod reports;
+mod safeguard;
+mod schema;
+mod timeseries;
+
+const DEFAULT_FROM: &str = "2024-05-15T00:00:00Z";
+const DEFAULT_TO: &str = "2024-05-16T00:00:00Z";
 
 #[tokio::main]
 async fn main() {
+    // Exit quietly when piped into `head` and the like instead of panicking on a closed stdout.
+    #[cfg(unix)]
+    unsafe {
+        libc::signal(libc::SIGPIPE, libc::SIG_DFL);
+    }
     dotenv::dotenv().ok();
+    if let Err(e) = run(Cli::parse()).await {
+        eprintln!("{} {}", "error:".red().bold(), e);
+        std::process::exit(e.exit_code());
+    }
+}
+
+async fn run(cli: Cli) -> Result<()> {
+    logging::init(
+        cli.verbose.min(2) as i8 - cli.quiet.min(2) as i8,
+        cli.log_format,
+        cli.log_file.as_deref(),
+    )?;
+
+    let output_file = match &cli.command {
+        Commands::Pipeline(args) => args.output.as_deref(),
+        Commands::Query(args) => args.output.as_deref(),
+        _ => None,
+    };
+    let format = cli.format.or(cli.json.then_some(cli::Format::Json));
+    let export_format = match format {
+        Some(format) if output_file.is_some() => format.file().ok_or("--output writes csv, ndjson or xlsx")?,
+        _ => export::Format::Csv,
+    };
+    if output_file.is_none() {
+        output::configure(
+            format
+                .map_or(Some(output::Format::Table), cli::Format::stdout)
+                .ok_or("--format xlsx needs --output")?,
+        );
+    }
+
+    let salt = env::var("REDACT_SALT").unwrap_or_default();
+    if !cli.hashed.is_empty() && salt.is_empty() {
+        return Err("--hash needs a salt in REDACT_SALT".into());
+    }
+    redact::configure(redact::Redaction {
+        enabled: cli.redact,
+        hashed: cli.hashed,
+        salt,
+    });
 
-    let matches = Command::new("mongo-utils")
-        .version("1.0")
-        .about("Performs different operations based on flags")
-        .arg(
-            Arg::new("merge")
-                .short('m')
-                .long("merge")
-                .help("Runs the merge function")
-                .action(clap::ArgAction::SetTrue),
-        )
-        .arg(
-            Arg::new("merge2")
-                .long("merge2")
-                .help("Runs the merge2 function with a specified value")
-                .value_name("VALUE")
-                .num_args(1)
-                .required(false),
-        )
-        .arg(
-            Arg::new("total")
-                .short('t')
-                .long("total")
-                .help("Runs the total_count function")
-                .action(clap::ArgAction::SetTrue),
-        )
-        .arg(
-            Arg::new("pipeline")
-                .short('p')
-                .long("pipeline")
-                .help("Runs the pipeline function")
-                .action(clap::ArgAction::SetTrue),
-        )
-        .arg(
-            Arg::new("duplicates")
-                .short('d')
-                .long("duplicates")
-                .help("Runs the duplicates function")
-                .action(clap::ArgAction::SetTrue),
-        )
-        .get_matches();
+    query::configure(query::Guard {
+        explain: cli.explain,
+        max_examined: cli.max_examined,
+        force: cli.force,
+    });
 
-    let mongodb_uri = env::var("MONGODB_URI").expect("MONGODB_URI must be set");
-    let partner = env::var("PARTNER").expect("PARTNER must be set");
-    let client = Client::with_uri_str(&mongodb_uri).await.unwrap();
-    let database = client.database("test");
-    let collection = database.collection::<Document>("users");
+    let mut target = config::Config::load(cli.config.as_deref().unwrap_or("profiles.json"), cli.config.is_some())
+        .and_then(|config| config::Target::resolve(&config, cli.profile.as_deref(), cli.database, cli.collection))?;
+    target.read_concern = cli.read_concern.or(target.read_concern);
+    target.max_staleness_seconds = cli.max_staleness.or(target.max_staleness_seconds);
 
-    if matches.get_flag("merge") {
-        merge_users::merge(&collection).await.unwrap();
-    } else if let Some(merge2_value) = matches.get_one::<String>("merge2") {
-        merge2::merge(&collection, merge2_value.parse::<u32>().unwrap())
-            .await
-            .unwrap();
-    } else if matches.get_flag("total") {
-        analytics::total_count(&collection, &partner).await;
-    } else if matches.get_flag("pipeline") {
-        analytics::pipeline(&collection).await.unwrap();
-    } else if matches.get_flag("duplicates") {
-        analytics::duplicates(&collection).await.unwrap();
-    } else {
-        eprintln!("No valid flag provided. Use --help for more information.");
+    match cli.command {
+        Commands::Merge { confirm } => {
+            safeguard::protect("merge", &target, confirm.allow_protected)?;
+            let collection = users(&target, Access::Write).await?;
+            let plan = merge_users::plan(&collection).await?;
+            safeguard::confirm("merge", &target, plan, confirm.yes)?;
+            merge_users::merge(&collection).await?;
+        }
+        Commands::Merge2 { limit, confirm } => {
+            safeguard::protect("merge2", &target, confirm.allow_protected)?;
+            let collection = users(&target, Access::Write).await?;
+            let plan = merge2::plan(&collection, limit).await?;
+            safeguard::confirm("merge2", &target, plan, confirm.yes)?;
+            merge2::merge(&collection, limit).await?;
+        }
+        Commands::Duplicates { window, top, compare } => {
+            let collection = users(&target, Access::Read).await?;
+            let (from, to) = window.or(DEFAULT_FROM, "2025-05-16T00:00:00Z");
+            if let Some(previous) = compare.compare_to {
+                let current = analytics::duplicate_report(&collection, from, to, top).await?;
+                let before = analytics::duplicate_report(&collection, previous.0, previous.1, top).await?;
+                compare::render(
+                    "Duplicate phones",
+                    (from, to),
+                    previous,
+                    &current.metrics(),
+                    &before.metrics(),
+                    compare.threshold,
+                )?;
+            } else {
+                analytics::duplicates(&collection, from, to, top).await?;
+            }
+        }
+        Commands::Pipeline(args) => {
+            let specs = specs(&args.spec)?;
+            let (from, to) = args.window.or(DEFAULT_FROM, DEFAULT_TO);
+            let limit = match args.output {
+                Some(_) => args.limit,
+                None => Some(args.limit.unwrap_or(5)),
+            };
+            let query = analytics::PipelineQuery::new(
+                &specs,
+                Some(&args.sort),
+                limit,
+                args.partner,
+                args.lender.as_deref(),
+                args.status,
+            )?;
+            let collection = users(&target, Access::Read).await?;
+            if let Some(output) = args.output {
+                let rows = export::pipeline(&collection, &specs, from, to, &query, &output, export_format).await?;
+                tracing::info!(rows, file = %output, "export written");
+            } else {
+                analytics::pipeline(&collection, &specs, from, to, &query).await?;
+            }
+        }
+        Commands::Query(args) => {
+            let limit = match args.output {
+                Some(_) => args.limit,
+                None => Some(args.limit.unwrap_or(adhoc::PAGE_SIZE)),
+            };
+            let query = adhoc::AdhocQuery::new(
+                args.filter.as_deref(),
+                args.projection.as_deref(),
+                args.sort.as_deref(),
+                args.agg.as_deref(),
+                limit,
+                args.page,
+            )?;
+            let collection = users(&target, Access::Read).await?;
+            if let Some(output) = args.output {
+                let rows = adhoc::export(&collection, &query, &output, export_format).await?;
+                tracing::info!(rows, file = %output, "export written");
+            } else {
+                adhoc::run(&collection, &query).await?;
+            }
+        }
+        Commands::Audit { samples } => {
+            audit::audit(&users(&target, Access::Read).await?, samples).await?;
+        }
+        Commands::Indexes { apply } => {
+            let access = if apply { Access::Write } else { Access::Read };
+            indexes::indexes(&users(&target, access).await?, apply).await?;
+        }
+        Commands::Schema { sample_size } => {
+            schema::schema(&users(&target, Access::Read).await?, sample_size).await?;
+        }
+        Commands::Report(report) => run_report(report, &target).await?,
     }
+    Ok(())
+}
+
+async fn run_report(report: Report, target: &config::Target) -> Result<()> {
+    match report {
+        Report::Partners {
+            partner,
+            window,
+            compare,
+        } => {
+            // Without a window this report counts all time, which has no period to compare.
+            if compare.compare_to.is_some() && (window.from.is_none() || window.to.is_none()) {
+                return Err("--compare-to needs --from and --to for report partners".into());
+            }
+            let partner = resolve_partner(partner, target)?;
+            let collection = users(target, Access::Read).await?;
+            let (from, to) = window.or(DEFAULT_FROM, DEFAULT_TO);
+            if let Some(previous) = compare.compare_to {
+                let current = analytics::partner_counts(&collection, &partner, Some((from, to))).await?;
+                let before = analytics::partner_counts(&collection, &partner, Some(previous)).await?;
+                let title = format!("Partner counts for {}", partner);
+                compare::render(&title, (from, to), previous, &current, &before, compare.threshold)?;
+            } else {
+                analytics::total_count(&collection, &partner, window.is_given().then_some((from, to))).await?;
+            }
+        }
+        Report::Lenders {
+            partner,
+            window,
+            compare,
+            spec,
+        } => {
+            let partner = resolve_partner(partner, target)?;
+            let specs = specs(&spec)?;
+            let collection = users(target, Access::Read).await?;
+            let (from, to) = window.or(DEFAULT_FROM, DEFAULT_TO);
+            if let Some(previous) = compare.compare_to {
+                let current = analytics::lender_funnel(&collection, &specs, &partner, from, to).await?;
+                let before = analytics::lender_funnel(&collection, &specs, &partner, previous.0, previous.1).await?;
+                let title = format!("Lender funnel for {}", partner);
+                compare::render(
+                    &title,
+                    (from, to),
+                    previous,
+                    &analytics::lender_metrics(&current),
+                    &analytics::lender_metrics(&before),
+                    compare.threshold,
+                )?;
+            } else {
+                analytics::lenders(&collection, &specs, &partner, from, to).await?;
+            }
+        }
+        Report::Distribution {
+            window,
+            partner,
+            by_partner,
+            mut buckets,
+            spec,
+        } => {
+            let (from, to) = window.or(DEFAULT_FROM, DEFAULT_TO);
+            buckets.sort_by(f64::total_cmp);
+            buckets.dedup();
+            distribution::distribution(
+                &users(target, Access::Read).await?,
+                &specs(&spec)?,
+                partner.as_deref(),
+                by_partner,
+                &buckets,
+                from,
+                to,
+            )
+            .await?;
+        }
+        Report::Overlap {
+            window,
+            partner,
+            approved,
+            spec,
+        } => {
+            let (from, to) = window.or(DEFAULT_FROM, DEFAULT_TO);
+            overlap::overlap(
+                &users(target, Access::Read).await?,
+                &specs(&spec)?,
+                partner.as_deref(),
+                approved,
+                from,
+                to,
+            )
+            .await?;
+        }
+        Report::Geo { window, partner, spec } => {
+            let (from, to) = window.or(DEFAULT_FROM, DEFAULT_TO);
+            geo::geo(
+                &users(target, Access::Read).await?,
+                &specs(&spec)?,
+                partner.as_deref(),
+                from,
+                to,
+            )
+            .await?;
+        }
+        Report::Timeseries {
+            window,
+            field,
+            unit,
+            by_partner,
+            chart,
+        } => {
+            let (from, to) = window.or(DEFAULT_FROM, DEFAULT_TO);
+            timeseries::timeseries(
+                &users(target, Access::Read).await?,
+                &field,
+                unit,
+                by_partner,
+                chart,
+                from,
+                to,
+            )
+            .await?;
+        }
+        Report::List { dir } => {
+            reports::list(&dir.path)?;
+        }
+        Report::Run { name, params, dir } => {
+            reports::run(&users(target, Access::Read).await?, &dir.path, &name, &params).await?;
+        }
+    }
+    Ok(())
+}
+
+/// The users collection of the selected profile, on the primary for `Access::Write`.
+async fn users(target: &config::Target, access: Access) -> Result<Collection<Document>> {
+    target.connect(access).await
+}
+
+/// `--partner`/`PARTNER`, falling back to the profile's default partner.
+fn resolve_partner(partner: Option<String>, target: &config::Target) -> Result<String> {
+    partner.or_else(|| target.partner.clone()).ok_or_else(|| {
+        Error::Validation("no partner: pass --partner, set PARTNER or give the profile a partner".into())
+    })
+}
+
+fn specs(spec: &cli::Spec) -> Result<Vec<lenders::LenderSpec>> {
+    lenders::load(spec.path.as_deref())
 }
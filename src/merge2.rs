@@ -1,14 +1,21 @@
+use crate::error::{Error, Result};
+use crate::logging;
+use crate::query;
+use crate::redact;
+use crate::safeguard::{self, Plan};
 use futures_util::future::join_all;
 use futures_util::stream::TryStreamExt;
-use mongodb::bson::{doc, Bson, DateTime, Document};
+use mongodb::bson::{doc, oid::ObjectId, Bson, DateTime, Document};
 use mongodb::options::{AggregateOptions, FindOptions, ReplaceOptions};
 use mongodb::Collection;
-use std::error::Error;
+use std::cmp::Reverse;
 use std::sync::Arc;
 use tokio::sync::Semaphore;
+use tracing::{error, info};
 
-pub async fn merge(collection: &Collection<Document>, limit: u32) -> Result<(), Box<dyn Error + Send + Sync>> {
-    let pipeline = vec![
+/// Up to `limit` phones shared by more than one user, with the number of users sharing each.
+fn duplicate_phones(limit: u32) -> Result<Vec<Document>> {
+    Ok(vec![
         doc! { "$match": {
                 "updatedAt": {
                 "$gte": DateTime::parse_rfc3339_str("2024-05-15T00:00:00Z")?,
@@ -17,49 +24,74 @@ pub async fn merge(collection: &Collection<Document>, limit: u32) -> Result<(),
         doc! { "$group": { "_id": "$phone", "count": { "$sum": 1 } } },
         doc! { "$match": { "count": { "$gt": 1 } } },
         doc! { "$sort": { "count": -1 } },
-        doc! { "$project": { "_id": 0, "phone": "$_id" } },
+        doc! { "$project": { "_id": 0, "phone": "$_id", "count": 1 } },
         doc! { "$limit": limit },
-    ];
+    ])
+}
+
+/// Users `merge` would replace (one per phone) and delete (the rest).
+pub async fn plan(collection: &Collection<Document>, limit: u32) -> Result<Plan> {
+    safeguard::merge_plan(collection, duplicate_phones(limit)?).await
+}
+
+pub async fn merge(collection: &Collection<Document>, limit: u32) -> Result<()> {
+    let pipeline = duplicate_phones(limit)?;
 
     let options = AggregateOptions::builder().batch_size(100).build();
-    let mut cursor = collection.aggregate(pipeline, options).await?;
+    let mut cursor = query::aggregate(collection, pipeline, options).await?;
 
     let semaphore = Arc::new(Semaphore::new(10));
     let collection = Arc::new(collection.clone());
 
     let mut tasks = Vec::new();
     let mut i = 1;
+    let run_id: Arc<str> = ObjectId::new().to_hex().into();
+    info!(target: logging::MERGE, run_id = %run_id, collection = collection.name(), limit, "merge started");
 
     while let Some(result) = cursor.try_next().await? {
-        if let Some(phone) = result.get_str("phone").ok() {
-            println!("{}: {}", i, phone);
+        if let Ok(phone) = result.get_str("phone") {
+            info!(run_id = %run_id, phone = %redact::phone(phone), n = i, "merging phone");
             i += 1;
 
             let sem_clone = semaphore.clone();
             let coll_clone = collection.clone();
+            let run_id = run_id.clone();
             let phone = phone.to_string();
 
             tasks.push(tokio::spawn(async move {
-                let _permit = sem_clone.acquire().await.unwrap();
-                if let Err(e) = process_phone(&coll_clone, &phone).await {
-                    eprintln!("Error processing phone {}: {:?}", phone, e);
+                let _permit = sem_clone.acquire().await;
+                let result = process_phone(&coll_clone, &phone, &run_id).await;
+                if let Err(e) = &result {
+                    error!(run_id = %run_id, phone = %redact::phone(&phone), error = %e, "merge of phone failed");
                 }
+                result
             }));
         }
     }
 
-    let results = join_all(tasks).await;
-    for result in results {
-        if let Err(e) = result {
-            eprintln!("Task error: {:?}", e);
+    let mut succeeded = 0;
+    let mut failures = Vec::new();
+    for result in join_all(tasks).await {
+        match result {
+            Ok(Ok(())) => succeeded += 1,
+            Ok(Err(e)) => failures.push(e),
+            Err(e) => failures.push(Error::Query(format!("merge task stopped: {}", e))),
         }
     }
 
-    println!("Done");
-    Ok(())
+    info!(target: logging::MERGE, run_id = %run_id, succeeded, failed = failures.len(), "merge finished");
+    match failures.len() {
+        0 => Ok(()),
+        _ if succeeded == 0 => Err(failures.remove(0)),
+        failed => Err(Error::PartialFailure {
+            succeeded,
+            failed: failed as u64,
+            first: failures[0].to_string(),
+        }),
+    }
 }
 
-async fn process_phone(collection: &Collection<Document>, phone: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+async fn process_phone(collection: &Collection<Document>, phone: &str, run_id: &str) -> Result<()> {
     let filter = doc! {"phone": phone};
     let find_options = FindOptions::builder()
         .projection(doc! {
@@ -70,14 +102,16 @@ async fn process_phone(collection: &Collection<Document>, phone: &str) -> Result
         })
         .build();
 
-    let users: Vec<Document> = collection.find(filter, find_options).await?.try_collect().await?;
+    let users: Vec<Document> = query::find(collection, filter, find_options)
+        .await?
+        .try_collect()
+        .await?;
 
+    if users.len() < 2 {
+        return Ok(());
+    }
     let mut sorted_users = users.clone();
-    sorted_users.sort_by(|a, b| {
-        b.get_datetime("updatedAt")
-            .unwrap()
-            .cmp(&a.get_datetime("updatedAt").unwrap())
-    });
+    sorted_users.sort_by_key(|user| Reverse(user.get_datetime("updatedAt").ok().copied()));
 
     let mut merged_user = sorted_users[0].clone();
     let mut merged_accounts: Vec<Bson> = Vec::new();
@@ -97,17 +131,23 @@ async fn process_phone(collection: &Collection<Document>, phone: &str) -> Result
     }
 
     let merged_id = merged_user.get_object_id("_id")?;
+    let duplicates: Vec<String> = sorted_users[1..]
+        .iter()
+        .filter_map(|user| user.get_object_id("_id").ok().map(|id| id.to_hex()))
+        .collect();
 
     // Update the merged user
     let replace_options = ReplaceOptions::builder().upsert(Some(true)).build();
     collection
         .replace_one(doc! {"_id": merged_id}, merged_user, Some(replace_options))
         .await?;
+    info!(target: logging::MERGE, run_id, phone = %redact::phone(phone), kept = %merged_id, merged = ?duplicates, "replaced kept user");
 
     // Delete other users
     for user in &sorted_users[1..] {
         let user_id = user.get_object_id("_id")?;
         collection.delete_one(doc! {"_id": user_id}, None).await?;
+        info!(target: logging::MERGE, run_id, phone = %redact::phone(phone), kept = %merged_id, deleted = %user_id, "deleted duplicate user");
     }
 
     Ok(())
@@ -1,60 +1,102 @@
+use crate::error::{Error, Result};
+use crate::logging;
+use crate::query;
+use crate::redact;
+use crate::safeguard::{self, Plan};
 use futures_util::stream::TryStreamExt;
 use mongodb::{
-    bson::{doc, Bson, DateTime, Document},
+    bson::{doc, oid::ObjectId, Bson, DateTime, Document},
     options::{AggregateOptions, FindOptions},
     Collection,
 };
-use std::error::Error;
+use std::cmp::Reverse;
+use tracing::{error, info};
 
-pub async fn merge(collection: &Collection<Document>) -> Result<(), Box<dyn Error>> {
-    let pipeline = vec![
+/// Phones shared by more than one user, with the number of users sharing each.
+fn duplicate_phones() -> Result<Vec<Document>> {
+    Ok(vec![
         doc! { "$match": { "updatedAt": { "$gte": DateTime::parse_rfc3339_str("2020-05-15T00:00:00Z")?, "$lt": DateTime::parse_rfc3339_str("2024-05-16T00:00:00Z")? } } },
         doc! { "$group": { "_id": "$phone", "count": { "$sum": 1 } } },
         doc! { "$match": { "count": { "$gt": 1 } } },
         doc! { "$sort": { "count": -1 } },
-        doc! { "$project": { "_id": 0, "phone": "$_id" } },
+        doc! { "$project": { "_id": 0, "phone": "$_id", "count": 1 } },
         doc! { "$limit": 1000 },
-    ];
-    let mut cursor = collection.aggregate(pipeline, AggregateOptions::default()).await?;
-    let mut i = 1;
+    ])
+}
+
+/// Users `merge` would replace (one per phone) and delete (the rest).
+pub async fn plan(collection: &Collection<Document>) -> Result<Plan> {
+    safeguard::merge_plan(collection, duplicate_phones()?).await
+}
+
+pub async fn merge(collection: &Collection<Document>) -> Result<()> {
+    let pipeline = duplicate_phones()?;
+    let mut cursor = query::aggregate(collection, pipeline, AggregateOptions::default()).await?;
+    let run_id = ObjectId::new().to_hex();
+    info!(target: logging::MERGE, run_id, collection = collection.name(), "merge started");
+    let mut merged = 0;
     while let Some(result) = cursor.try_next().await? {
-        if let Some(phone) = result.get_str("phone").ok() {
-            println!("{}: {}", i, phone);
-            i += 1;
-            let filter = doc! {"phone": phone};
-            let find_options = FindOptions::default();
-            let users: Vec<Document> = collection.find(filter, find_options).await?.try_collect().await?;
-            let mut sorted_users = users.clone();
-            sorted_users.sort_by(|a, b| {
-                b.get_datetime("updatedAt")
-                    .unwrap()
-                    .cmp(&a.get_datetime("updatedAt").unwrap())
-            });
-            let mut merged_user = sorted_users[0].clone();
-            let mut merged_accounts: Vec<Bson> = Vec::new();
-            for user in &sorted_users {
-                if let Ok(accounts) = user.get_array("accounts") {
-                    merged_accounts.extend(accounts.iter().cloned());
+        if let Ok(phone) = result.get_str("phone") {
+            info!(run_id, phone = %redact::phone(phone), n = merged + 1, "merging phone");
+            if let Err(e) = merge_phone(collection, phone, &run_id).await {
+                error!(run_id, phone = %redact::phone(phone), error = %e, "merge of phone failed");
+                if merged == 0 {
+                    return Err(e);
                 }
+                return Err(Error::PartialFailure {
+                    succeeded: merged,
+                    failed: 1,
+                    first: format!("{}: {}", redact::phone(phone), e),
+                });
             }
-            merged_user.insert("accounts", Bson::Array(merged_accounts));
-            for user in &sorted_users[1..] {
-                for (key, value) in user.iter() {
-                    if key != "accounts" && key != "_id" && key != "updatedAt" && !merged_user.contains_key(key) {
-                        merged_user.insert(key, value.clone());
-                    }
-                }
-            }
-            let merged_id = merged_user.get_object_id("_id")?;
-            collection
-                .replace_one(doc! {"_id": merged_id}, merged_user, None)
-                .await?;
-            for user in &sorted_users[1..] {
-                let user_id = user.get_object_id("_id")?;
-                collection.delete_one(doc! {"_id": user_id}, None).await?;
+            merged += 1;
+        }
+    }
+    info!(target: logging::MERGE, run_id, merged, "merge finished");
+    Ok(())
+}
+
+/// Keeps the most recently updated user of `phone` with every user's accounts and deletes the rest.
+async fn merge_phone(collection: &Collection<Document>, phone: &str, run_id: &str) -> Result<()> {
+    let filter = doc! {"phone": phone};
+    let find_options = FindOptions::default();
+    let users: Vec<Document> = query::find(collection, filter, find_options)
+        .await?
+        .try_collect()
+        .await?;
+    if users.len() < 2 {
+        return Ok(());
+    }
+    let mut sorted_users = users.clone();
+    sorted_users.sort_by_key(|user| Reverse(user.get_datetime("updatedAt").ok().copied()));
+    let mut merged_user = sorted_users[0].clone();
+    let mut merged_accounts: Vec<Bson> = Vec::new();
+    for user in &sorted_users {
+        if let Ok(accounts) = user.get_array("accounts") {
+            merged_accounts.extend(accounts.iter().cloned());
+        }
+    }
+    merged_user.insert("accounts", Bson::Array(merged_accounts));
+    for user in &sorted_users[1..] {
+        for (key, value) in user.iter() {
+            if key != "accounts" && key != "_id" && key != "updatedAt" && !merged_user.contains_key(key) {
+                merged_user.insert(key, value.clone());
             }
         }
     }
-    println!("Done");
+    let merged_id = merged_user.get_object_id("_id")?;
+    let duplicates: Vec<String> = sorted_users[1..]
+        .iter()
+        .filter_map(|user| user.get_object_id("_id").ok().map(|id| id.to_hex()))
+        .collect();
+    collection
+        .replace_one(doc! {"_id": merged_id}, merged_user, None)
+        .await?;
+    info!(target: logging::MERGE, run_id, phone = %redact::phone(phone), kept = %merged_id, merged = ?duplicates, "replaced kept user");
+    for user in &sorted_users[1..] {
+        let user_id = user.get_object_id("_id")?;
+        collection.delete_one(doc! {"_id": user_id}, None).await?;
+        info!(target: logging::MERGE, run_id, phone = %redact::phone(phone), kept = %merged_id, deleted = %user_id, "deleted duplicate user");
+    }
     Ok(())
 }
@@ -0,0 +1,209 @@
+//! Every command hands its results to this module as flat records so `--format` applies to all of
+//! them alike. The default `table` format keeps each command's own terminal view.
+
+use crate::error::Result;
+use crate::export::{cell, lookup};
+use crate::redact;
+use crate::render;
+use colored::*;
+use mongodb::bson::{Bson, Document};
+use serde_json::Value;
+use std::borrow::Cow;
+use std::io::{self, Stdout, Write};
+use std::sync::OnceLock;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    #[default]
+    Table,
+    Json,
+    Ndjson,
+    Csv,
+    Yaml,
+}
+
+static FORMAT: OnceLock<Format> = OnceLock::new();
+
+pub fn configure(format: Format) {
+    let _ = FORMAT.set(format);
+}
+
+pub fn format() -> Format {
+    FORMAT.get().copied().unwrap_or_default()
+}
+
+/// Emits a command's result: `table` prints its terminal view in the table format, any other
+/// format serializes `records`.
+pub fn emit(records: &[Document], table: impl FnOnce()) -> Result<()> {
+    if format() == Format::Table {
+        table();
+        return Ok(());
+    }
+    let mut printer = Printer {
+        redact: false,
+        ..Printer::new(Vec::new())
+    };
+    for record in records {
+        printer.record(record)?;
+    }
+    printer.finish()
+}
+
+/// Streams records to stdout one at a time. In the table format each record is printed as a
+/// colorized document.
+pub struct Printer {
+    format: Format,
+    /// Records are user documents and go through `--redact`.
+    redact: bool,
+    /// CSV columns. When none are given, records are held until `finish` and every key seen in
+    /// any of them becomes a column.
+    columns: Vec<String>,
+    pending: Option<Vec<Document>>,
+    csv: Option<csv::Writer<Stdout>>,
+    count: u64,
+}
+
+impl Printer {
+    pub fn new(columns: Vec<String>) -> Self {
+        Printer {
+            format: format(),
+            redact: true,
+            pending: columns.is_empty().then(Vec::new),
+            columns,
+            csv: None,
+            count: 0,
+        }
+    }
+
+    pub fn record(&mut self, record: &Document) -> Result<()> {
+        let record = if self.redact {
+            redact::document(record)
+        } else {
+            Cow::Borrowed(record)
+        };
+        let record = record.as_ref();
+        match self.format {
+            Format::Table => println!("{}", render::colorize_document(record, 0)),
+            Format::Json => {
+                let separator = if self.count == 0 { "[\n  " } else { ",\n  " };
+                print!("{}{}", separator, serde_json::to_string(&plain_document(record))?);
+            }
+            Format::Ndjson => println!("{}", serde_json::to_string(&plain_document(record))?),
+            Format::Csv => match self.pending.as_mut() {
+                Some(pending) => pending.push(record.clone()),
+                None => self.write_csv(record)?,
+            },
+            Format::Yaml => print!("{}", serde_yaml::to_string(&[plain_document(record)])?),
+        }
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Number of records printed so far.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    fn write_csv(&mut self, record: &Document) -> Result<()> {
+        let writer = match self.csv.as_mut() {
+            Some(writer) => writer,
+            None => {
+                let mut writer = csv::Writer::from_writer(io::stdout());
+                writer.write_record(&self.columns)?;
+                self.csv.insert(writer)
+            }
+        };
+        writer.write_record(self.columns.iter().map(|c| cell(lookup(record, c))))?;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<()> {
+        if let Some(pending) = self.pending.take() {
+            for record in &pending {
+                for key in record.keys() {
+                    if !self.columns.contains(key) {
+                        self.columns.push(key.clone());
+                    }
+                }
+            }
+            for record in &pending {
+                self.write_csv(record)?;
+            }
+        }
+        match self.format {
+            Format::Json if self.count == 0 => println!("[]"),
+            Format::Json => println!("\n]"),
+            Format::Yaml if self.count == 0 => println!("[]"),
+            Format::Csv => {
+                if let Some(mut writer) = self.csv {
+                    writer.flush()?;
+                } else if !self.columns.is_empty() {
+                    println!("{}", self.columns.join(","));
+                }
+            }
+            _ => {}
+        }
+        io::stdout().flush()?;
+        Ok(())
+    }
+}
+
+/// Prints `records` as an aligned table with a bold header, for commands without a view of their own.
+pub fn table(records: &[Document]) {
+    let mut columns: Vec<&String> = Vec::new();
+    for record in records {
+        for key in record.keys() {
+            if !columns.contains(&key) {
+                columns.push(key);
+            }
+        }
+    }
+    let rows: Vec<Vec<String>> = records
+        .iter()
+        .map(|record| columns.iter().map(|c| cell(record.get(c.as_str()))).collect())
+        .collect();
+    let widths: Vec<usize> = columns
+        .iter()
+        .enumerate()
+        .map(|(i, c)| {
+            rows.iter()
+                .map(|r| r[i].chars().count())
+                .chain([c.chars().count()])
+                .max()
+                .unwrap_or(0)
+        })
+        .collect();
+
+    let line = |cells: &mut dyn Iterator<Item = &str>| {
+        cells
+            .zip(&widths)
+            .map(|(c, w)| format!("{:<w$}", c, w = w))
+            .collect::<Vec<_>>()
+            .join("  ")
+    };
+    println!("{}", line(&mut columns.iter().map(|c| c.as_str())).bold());
+    for row in &rows {
+        println!("{}", line(&mut row.iter().map(String::as_str)));
+    }
+    println!("{}", format!("{} rows", rows.len()).bright_black());
+}
+
+/// Plain JSON for scripts: dates as RFC 3339 strings, ObjectIds as hex and numbers unwrapped,
+/// rather than extended JSON wrappers.
+pub fn plain(value: &Bson) -> Value {
+    match value {
+        Bson::Document(doc) => plain_document(doc),
+        Bson::Array(items) => Value::Array(items.iter().map(plain).collect()),
+        Bson::DateTime(date) => Value::String(date.try_to_rfc3339_string().unwrap_or_else(|_| date.to_string())),
+        Bson::ObjectId(id) => Value::String(id.to_hex()),
+        Bson::Int32(n) => Value::from(*n),
+        Bson::Int64(n) => Value::from(*n),
+        Bson::Double(n) => serde_json::Number::from_f64(*n).map_or(Value::Null, Value::Number),
+        Bson::Decimal128(d) => Value::String(d.to_string()),
+        other => other.clone().into_relaxed_extjson(),
+    }
+}
+
+fn plain_document(doc: &Document) -> Value {
+    Value::Object(doc.iter().map(|(k, v)| (k.clone(), plain(v))).collect())
+}
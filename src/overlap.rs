@@ -0,0 +1,137 @@
+use crate::analytics::as_f64;
+use crate::chart;
+use crate::error::Result;
+use crate::lenders::{self, LenderSpec};
+use crate::output;
+use crate::query;
+use colored::*;
+use futures_util::stream::TryStreamExt;
+use mongodb::bson::{doc, Bson, DateTime, Document};
+use mongodb::Collection;
+use std::collections::HashMap;
+
+const CELL_WIDTH: usize = 9;
+
+/// Prints an N×N matrix over the spec's lenders where each cell counts users with accounts at both
+/// the row and the column lender. The diagonal is the number of users at that lender alone.
+///
+/// With `approved_only`, a user only counts for a lender that made an offer or reported one of the
+/// spec's `approved` statuses. Lenders with neither in the spec are shown as n/a.
+pub async fn overlap(
+    collection: &Collection<Document>,
+    specs: &[LenderSpec],
+    partner: Option<&str>,
+    approved_only: bool,
+    from: DateTime,
+    to: DateTime,
+) -> Result<()> {
+    let mut filter = doc! { "updatedAt": { "$gte": from, "$lt": to } };
+    if let Some(partner) = partner {
+        filter.insert("partner", partner);
+    }
+    let names: Vec<Bson> = specs
+        .iter()
+        .map(|spec| {
+            let counts = if approved_only {
+                lenders::approved(spec)
+            } else {
+                lenders::submitted(spec)
+            };
+            Bson::Document(doc! { "$cond": [counts, spec.name.as_str(), null] })
+        })
+        .collect();
+
+    let mut pipeline = vec![doc! { "$match": filter }, doc! { "$project": { "accounts": 1 } }];
+    pipeline.extend(lenders::stages(specs));
+    pipeline.extend([
+        doc! { "$project": { "_id": 0, "lenders": { "$filter": { "input": names, "cond": { "$ne": ["$$this", null] } } } } },
+        doc! { "$project": { "a": "$lenders", "b": "$lenders" } },
+        doc! { "$unwind": "$a" },
+        doc! { "$unwind": "$b" },
+        doc! { "$group": { "_id": { "a": "$a", "b": "$b" }, "users": { "$sum": 1 } } },
+    ]);
+    let mut cursor = query::aggregate(collection, pipeline, None).await?;
+
+    let mut cells: HashMap<(String, String), f64> = HashMap::new();
+    while let Some(doc) = cursor.try_next().await? {
+        let id = doc.get_document("_id")?;
+        cells.insert(
+            (id.get_str("a")?.to_string(), id.get_str("b")?.to_string()),
+            as_f64(doc.get("users")),
+        );
+    }
+
+    // None when approvals cannot be told apart for one of the two lenders.
+    let users = |row: &LenderSpec, column: &LenderSpec| {
+        if approved_only && !(row.reports_approvals() && column.reports_approvals()) {
+            return None;
+        }
+        Some(
+            cells
+                .get(&(row.name.clone(), column.name.clone()))
+                .copied()
+                .unwrap_or(0.0),
+        )
+    };
+    let records: Vec<Document> = specs
+        .iter()
+        .map(|row| {
+            let mut record = doc! { "lender": row.name.as_str() };
+            for column in specs {
+                record.insert(column.key.as_str(), users(row, column));
+            }
+            record
+        })
+        .collect();
+    let unknown: Vec<&str> = specs
+        .iter()
+        .filter(|spec| approved_only && !spec.reports_approvals())
+        .map(|spec| spec.name.as_str())
+        .collect();
+
+    output::emit(&records, || {
+        println!(
+            "Lender overlap, {} users ({} to {})",
+            if approved_only { "approved" } else { "submitted" },
+            from,
+            to
+        );
+        let mut header = format!("{:<20}", "");
+        for spec in specs {
+            header.push_str(&format!(
+                " {:>w$}",
+                chart::truncate(&spec.key, CELL_WIDTH),
+                w = CELL_WIDTH
+            ));
+        }
+        println!("{}", header.bold());
+        for row in specs {
+            let mut line = format!("{:<20}", chart::truncate(&row.name, 20).green());
+            for column in specs {
+                let Some(users) = users(row, column) else {
+                    line.push_str(&format!(" {}", format!("{:>w$}", "n/a", w = CELL_WIDTH).bright_black()));
+                    continue;
+                };
+                let cell = format!("{:>w$}", users, w = CELL_WIDTH);
+                if row.name == column.name {
+                    line.push_str(&format!(" {}", cell.yellow().bold()));
+                } else if users > 0.0 {
+                    line.push_str(&format!(" {}", cell));
+                } else {
+                    line.push_str(&format!(" {}", cell.bright_black()));
+                }
+            }
+            println!("{}", line);
+        }
+        if !unknown.is_empty() {
+            println!(
+                "{}",
+                format!(
+                    "n/a: no loan amount or approved statuses in the lender spec for {}",
+                    unknown.join(", ")
+                )
+                .bright_black()
+            );
+        }
+    })
+}
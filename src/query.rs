@@ -0,0 +1,407 @@
+//! Every read the tool issues goes through this module so `--explain` and `--max-examined` apply to
+//! all commands alike.
+//!
+//! The cost guard only asks the planner for the winning plan, which does not run the query, and
+//! estimates from it how many documents the query examines:
+//!
+//! - a plan that scans the whole collection (`COLLSCAN`) examines every document in it;
+//! - an index plan examines the documents its leading filter matches, which the server counts
+//!   from the same index;
+//! - a `$lookup` whose foreign field has no index scans the joined collection once for each
+//!   document that reaches it.
+//!
+//! Queries over `--max-examined` are refused. Only `--explain` runs the query under
+//! `executionStats` to report what it actually examined.
+
+use crate::error::Error as CrateError;
+use crate::redact;
+use colored::*;
+use futures_util::stream::TryStreamExt;
+use mongodb::bson::{doc, Bson, Document};
+use mongodb::options::{AggregateOptions, FindOptions};
+use mongodb::{Collection, Cursor};
+use std::error::Error;
+use std::fmt;
+use std::sync::OnceLock;
+
+type QueryResult<T> = Result<T, QueryError>;
+
+#[derive(Debug)]
+pub enum QueryError {
+    Mongo(mongodb::error::Error),
+    /// The query is estimated to examine more documents than `--max-examined` allows.
+    TooExpensive {
+        query: String,
+        examined: u64,
+        limit: u64,
+    },
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueryError::Mongo(e) => write!(f, "{}", e),
+            QueryError::TooExpensive { query, examined, limit } => write!(
+                f,
+                "{} would examine about {} documents (limit {}); rerun with --force to run it anyway",
+                query, examined, limit
+            ),
+        }
+    }
+}
+
+impl Error for QueryError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            QueryError::Mongo(e) => Some(e),
+            QueryError::TooExpensive { .. } => None,
+        }
+    }
+}
+
+impl From<mongodb::error::Error> for QueryError {
+    fn from(e: mongodb::error::Error) -> Self {
+        QueryError::Mongo(e)
+    }
+}
+
+/// Global explain and cost settings, configured once from the command line.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Guard {
+    /// Print each query's plan summary instead of running it.
+    pub explain: bool,
+    /// Refuse queries estimated to examine more documents than this.
+    pub max_examined: Option<u64>,
+    /// Run queries over `max_examined` anyway.
+    pub force: bool,
+}
+
+static GUARD: OnceLock<Guard> = OnceLock::new();
+
+pub fn configure(guard: Guard) {
+    let _ = GUARD.set(guard);
+}
+
+fn guard() -> Guard {
+    GUARD.get().copied().unwrap_or_default()
+}
+
+/// A filter that matches nothing and is answered without touching the collection, used to keep
+/// commands running (over empty results) in `--explain` mode.
+fn nothing() -> Document {
+    doc! { "_id": { "$in": [] } }
+}
+
+/// Fails when a user-supplied pipeline writes: `$out` or `$merge` anywhere in it, including the
+/// sub-pipelines of `$facet`, `$lookup` and `$unionWith`. Writes go through the merge commands,
+/// which confirm their target first.
+pub fn read_only(pipeline: &[Document]) -> Result<(), CrateError> {
+    fn writes(doc: &Document) -> Option<&str> {
+        doc.iter().find_map(|(key, value)| match value {
+            _ if key == "$out" || key == "$merge" => Some(key.as_str()),
+            Bson::Document(child) => writes(child),
+            Bson::Array(items) => items.iter().filter_map(Bson::as_document).find_map(writes),
+            _ => None,
+        })
+    }
+    match pipeline.iter().find_map(writes) {
+        Some(stage) => Err(CrateError::Validation(format!(
+            "{} writes to a collection; only read-only pipelines can be run",
+            stage
+        ))),
+        None => Ok(()),
+    }
+}
+
+pub async fn aggregate(
+    collection: &Collection<Document>,
+    mut pipeline: Vec<Document>,
+    options: impl Into<Option<AggregateOptions>>,
+) -> QueryResult<Cursor<Document>> {
+    let command = doc! { "aggregate": collection.name(), "pipeline": pipeline.clone(), "cursor": {} };
+    if check(collection, "aggregate", command).await? {
+        pipeline.insert(0, doc! { "$match": nothing() });
+    }
+    Ok(collection.aggregate(pipeline, options).await?)
+}
+
+pub async fn find(
+    collection: &Collection<Document>,
+    filter: Document,
+    options: impl Into<Option<FindOptions>>,
+) -> QueryResult<Cursor<Document>> {
+    let options = options.into();
+    let mut command = doc! { "find": collection.name(), "filter": filter.clone() };
+    if let Some(projection) = options.as_ref().and_then(|o| o.projection.clone()) {
+        command.insert("projection", projection);
+    }
+    if let Some(sort) = options.as_ref().and_then(|o| o.sort.clone()) {
+        command.insert("sort", sort);
+    }
+    if let Some(limit) = options.as_ref().and_then(|o| o.limit) {
+        command.insert("limit", limit);
+    }
+    let filter = if check(collection, "find", command).await? {
+        nothing()
+    } else {
+        filter
+    };
+    Ok(collection.find(filter, options).await?)
+}
+
+pub async fn count(collection: &Collection<Document>, filter: Document) -> QueryResult<u64> {
+    // count_documents runs this pipeline on the server
+    let pipeline = vec![
+        doc! { "$match": filter.clone() },
+        doc! { "$group": { "_id": 1, "n": { "$sum": 1 } } },
+    ];
+    let command = doc! { "aggregate": collection.name(), "pipeline": pipeline, "cursor": {} };
+    if check(collection, "count", command).await? {
+        return Ok(0);
+    }
+    Ok(collection.count_documents(filter, None).await?)
+}
+
+/// Explains `command` when the guard needs it. Returns true when the query should not run because
+/// only its plan was requested; errors when it would examine more than `--max-examined` documents.
+async fn check(collection: &Collection<Document>, kind: &str, command: Document) -> QueryResult<bool> {
+    tracing::debug!(collection = collection.name(), kind, command = %redact::document(&command), "query");
+    let guard = guard();
+    let limited = guard.max_examined.is_some() && !guard.force;
+    if !guard.explain && !limited {
+        return Ok(false);
+    }
+
+    // executionStats runs the query, so only --explain, which reports what it examined, asks for it.
+    let verbosity = if guard.explain {
+        "executionStats"
+    } else {
+        "queryPlanner"
+    };
+    let namespace = collection.namespace();
+    let plan = collection
+        .client()
+        .database(&namespace.db)
+        .run_command(
+            doc! { "explain": command.clone(), "verbosity": verbosity },
+            collection.selection_criteria().cloned(),
+        )
+        .await?;
+    let summary = Summary::from_explain(&plan);
+
+    if guard.explain {
+        summary.print(&format!("{}.{}", namespace.coll, kind));
+    }
+    if let (Some(max), false) = (guard.max_examined, guard.force) {
+        let examined = estimate(collection, &command, &summary).await?;
+        if examined > max {
+            return Err(QueryError::TooExpensive {
+                query: format!("{}.{}", namespace.coll, kind),
+                examined,
+                limit: max,
+            });
+        }
+    }
+    Ok(guard.explain)
+}
+
+/// Documents `command` examines, judged from its plan as described in the module docs.
+async fn estimate(collection: &Collection<Document>, command: &Document, summary: &Summary) -> QueryResult<u64> {
+    let collscan = summary.stages.iter().any(|stage| stage == "COLLSCAN");
+    let mut examined = match leading_filter(command) {
+        Some(filter) if !collscan => collection.count_documents(filter, None).await?,
+        _ => collection.estimated_document_count(None).await?,
+    };
+
+    let mut joins = Vec::new();
+    if let Ok(pipeline) = command.get_array("pipeline") {
+        for stage in pipeline.iter().filter_map(Bson::as_document) {
+            collect_key(stage, "$lookup", &mut joins);
+        }
+    }
+    let database = collection.client().database(&collection.namespace().db);
+    for join in joins {
+        let Ok(from) = join.get_str("from") else {
+            continue;
+        };
+        let from = database.collection::<Document>(from);
+        if indexed(&from, join.get_str("foreignField").ok()).await {
+            continue;
+        }
+        let scanned = from.estimated_document_count(None).await?;
+        examined = examined.saturating_add(scanned.saturating_mul(examined.max(1)));
+    }
+    Ok(examined)
+}
+
+/// The find filter, or the `$match` a pipeline starts with.
+fn leading_filter(command: &Document) -> Option<Document> {
+    if let Ok(filter) = command.get_document("filter") {
+        return Some(filter.clone());
+    }
+    let first = command.get_array("pipeline").ok()?.first()?.as_document()?;
+    first.get_document("$match").ok().cloned()
+}
+
+/// Whether an index of `collection` starts with `field`. A collection that cannot be listed, e.g.
+/// because it does not exist, counts as unindexed.
+async fn indexed(collection: &Collection<Document>, field: Option<&str>) -> bool {
+    let Some(field) = field else {
+        return false;
+    };
+    let Ok(indexes) = collection.list_indexes(None).await else {
+        return false;
+    };
+    let indexes: Vec<_> = indexes.try_collect().await.unwrap_or_default();
+    indexes
+        .iter()
+        .any(|index| index.keys.keys().next().map(String::as_str) == Some(field))
+}
+
+#[derive(Debug, Default)]
+struct Summary {
+    stages: Vec<String>,
+    indexes: Vec<String>,
+    docs_examined: u64,
+    keys_examined: u64,
+    returned: u64,
+    millis: u64,
+}
+
+impl Summary {
+    fn from_explain(plan: &Document) -> Self {
+        let mut summary = Summary::default();
+        if let Some(winning) = find_key(plan, "winningPlan") {
+            summary.walk_plan(winning);
+        }
+        let mut stats = Vec::new();
+        collect_key(plan, "executionStats", &mut stats);
+        for stats in stats {
+            summary.docs_examined += number(stats.get("totalDocsExamined"));
+            summary.keys_examined += number(stats.get("totalKeysExamined"));
+            summary.returned += number(stats.get("nReturned"));
+            summary.millis = summary.millis.max(number(stats.get("executionTimeMillis")));
+        }
+        summary
+    }
+
+    /// Records stage names from the root down and the index of every index scan.
+    fn walk_plan(&mut self, plan: &Document) {
+        if let Ok(stage) = plan.get_str("stage") {
+            self.stages.push(stage.to_string());
+        }
+        if let Ok(index) = plan.get_str("indexName") {
+            self.indexes.push(index.to_string());
+        }
+        for key in ["queryPlan", "inputStage"] {
+            if let Ok(child) = plan.get_document(key) {
+                self.walk_plan(child);
+            }
+        }
+        if let Ok(children) = plan.get_array("inputStages") {
+            for child in children.iter().filter_map(Bson::as_document) {
+                self.walk_plan(child);
+            }
+        }
+    }
+
+    fn print(&self, label: &str) {
+        let plan = if self.stages.is_empty() {
+            "(unknown)".to_string()
+        } else {
+            self.stages.join(" <- ")
+        };
+        let index = if self.indexes.is_empty() {
+            "none".red().to_string()
+        } else {
+            self.indexes.join(", ").green().to_string()
+        };
+        eprintln!("{} {}", "explain".bold(), label.cyan());
+        eprintln!("  plan:          {}", plan);
+        eprintln!("  index:         {}", index);
+        eprintln!(
+            "  examined:      {} docs, {} keys",
+            self.docs_examined.to_string().yellow(),
+            self.keys_examined.to_string().yellow()
+        );
+        eprintln!("  returned:      {} in {} ms", self.returned, self.millis);
+    }
+}
+
+/// First document stored under `key` anywhere inside `doc`, depth first.
+fn find_key<'a>(doc: &'a Document, key: &str) -> Option<&'a Document> {
+    if let Ok(found) = doc.get_document(key) {
+        return Some(found);
+    }
+    doc.values().find_map(|value| match value {
+        Bson::Document(child) => find_key(child, key),
+        Bson::Array(items) => items
+            .iter()
+            .filter_map(Bson::as_document)
+            .find_map(|c| find_key(c, key)),
+        _ => None,
+    })
+}
+
+/// Every document stored under `key` inside `doc`, e.g. one `executionStats` per shard or `$cursor` stage.
+fn collect_key<'a>(doc: &'a Document, key: &str, found: &mut Vec<&'a Document>) {
+    for (k, value) in doc {
+        match value {
+            Bson::Document(child) if k == key => found.push(child),
+            Bson::Document(child) => collect_key(child, key, found),
+            Bson::Array(items) => {
+                for child in items.iter().filter_map(Bson::as_document) {
+                    collect_key(child, key, found);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn number(value: Option<&Bson>) -> u64 {
+    match value {
+        Some(Bson::Int32(n)) => *n as u64,
+        Some(Bson::Int64(n)) => *n as u64,
+        Some(Bson::Double(n)) => *n as u64,
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn leading_filter_of_a_find_and_a_pipeline() {
+        let find = doc! { "find": "users", "filter": { "partner": "p" } };
+        assert_eq!(leading_filter(&find), Some(doc! { "partner": "p" }));
+        let aggregate = doc! { "aggregate": "users", "pipeline": [{ "$match": { "phone": "1" } }, { "$count": "n" }] };
+        assert_eq!(leading_filter(&aggregate), Some(doc! { "phone": "1" }));
+        let unfiltered = doc! { "aggregate": "users", "pipeline": [{ "$group": { "_id": "$partner" } }] };
+        assert_eq!(leading_filter(&unfiltered), None);
+    }
+
+    #[test]
+    fn read_only_accepts_plain_pipelines() {
+        let pipeline = vec![
+            doc! { "$match": { "partner": "p" } },
+            doc! { "$group": { "_id": "$phone", "count": { "$sum": 1 } } },
+        ];
+        assert!(read_only(&pipeline).is_ok());
+    }
+
+    #[test]
+    fn read_only_rejects_out_and_merge() {
+        assert!(read_only(&[doc! { "$match": {} }, doc! { "$out": "copy" }]).is_err());
+        assert!(read_only(&[doc! { "$merge": { "into": "copy" } }]).is_err());
+    }
+
+    #[test]
+    fn read_only_rejects_writes_in_sub_pipelines() {
+        let facet = doc! { "$facet": { "a": [{ "$match": {} }], "b": [{ "$out": "copy" }] } };
+        assert!(read_only(&[facet]).is_err());
+        let lookup = doc! { "$lookup": { "from": "users", "as": "u", "pipeline": [{ "$merge": "copy" }] } };
+        assert!(read_only(&[lookup]).is_err());
+    }
+}
@@ -0,0 +1,219 @@
+//! `--redact` support: masks phone numbers, reduces names to initials and replaces chosen fields
+//! with salted hashes before anything reaches the terminal, a log line or an export file.
+
+use crate::export::cell;
+use mongodb::bson::{Bson, Document};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::sync::OnceLock;
+
+#[derive(Debug, Default, Clone)]
+pub struct Redaction {
+    pub enabled: bool,
+    /// Field names or dotted paths replaced by a salted hash, so values can still be joined on.
+    pub hashed: Vec<String>,
+    pub salt: String,
+}
+
+static REDACTION: OnceLock<Redaction> = OnceLock::new();
+
+pub fn configure(redaction: Redaction) {
+    let _ = REDACTION.set(redaction);
+}
+
+fn redaction() -> Option<&'static Redaction> {
+    REDACTION.get().filter(|r| r.enabled)
+}
+
+/// Masks all but the first and last two digits, e.g. `9876543210` becomes `98******10`.
+pub fn phone(phone: &str) -> Cow<'_, str> {
+    if redaction().is_none() {
+        return Cow::Borrowed(phone);
+    }
+    Cow::Owned(mask(phone))
+}
+
+fn mask(phone: &str) -> String {
+    let chars: Vec<char> = phone.chars().collect();
+    if chars.len() <= 4 {
+        return "*".repeat(chars.len());
+    }
+    let mut masked: String = chars[..2].iter().collect();
+    masked.push_str(&"*".repeat(chars.len() - 4));
+    masked.extend(&chars[chars.len() - 2..]);
+    masked
+}
+
+/// A copy of `doc` with every sensitive field redacted, or `doc` itself when redaction is off.
+pub fn document(doc: &Document) -> Cow<'_, Document> {
+    match redaction() {
+        Some(redaction) => Cow::Owned(redact_document(redaction, doc, "")),
+        None => Cow::Borrowed(doc),
+    }
+}
+
+fn redact_document(redaction: &Redaction, doc: &Document, prefix: &str) -> Document {
+    doc.iter()
+        .map(|(key, value)| {
+            let path = if prefix.is_empty() {
+                key.clone()
+            } else {
+                format!("{}.{}", prefix, key)
+            };
+            (key.clone(), redact_value(redaction, key, &path, value))
+        })
+        .collect()
+}
+
+fn redact_value(redaction: &Redaction, key: &str, path: &str, value: &Bson) -> Bson {
+    if matches!(value, Bson::Null) {
+        return Bson::Null;
+    }
+    if redaction.hashed.iter().any(|field| field == key || field == path) {
+        return Bson::String(hash(&redaction.salt, &cell(Some(value))));
+    }
+    match value {
+        Bson::Document(doc) => Bson::Document(redact_document(redaction, doc, path)),
+        Bson::Array(items) => Bson::Array(items.iter().map(|v| redact_value(redaction, key, path, v)).collect()),
+        Bson::String(_) | Bson::Int32(_) | Bson::Int64(_) if is_phone(key) => Bson::String(mask(&cell(Some(value)))),
+        Bson::String(id) if key == "_id" && looks_like_phone(id) => Bson::String(mask(id)),
+        Bson::String(name) if is_name(key, path) => Bson::String(initials(name)),
+        other => other.clone(),
+    }
+}
+
+/// `phone` and `mobile`, with an optional `Number` suffix or a prefix as in `alt_phone` or
+/// `alternateMobile`. Counts such as `phoneCount` or `duplicatePhones` are left alone.
+fn is_phone(key: &str) -> bool {
+    let key = key.to_ascii_lowercase().replace('_', "");
+    let key = key.strip_suffix("number").unwrap_or(&key);
+    key.ends_with("phone") || key.ends_with("mobile")
+}
+
+/// A group `_id` that is a phone number, as in results grouped by `$phone`: 10 to 13 digits with an
+/// optional leading `+`.
+fn looks_like_phone(id: &str) -> bool {
+    let digits = id.strip_prefix('+').unwrap_or(id);
+    (10..=13).contains(&digits.len()) && digits.bytes().all(|b| b.is_ascii_digit())
+}
+
+/// The user's `name` at the top level, and first/last/full name fields anywhere. A nested `name`
+/// is left alone since in `accounts` it is the lender.
+fn is_name(key: &str, path: &str) -> bool {
+    let key = key.to_ascii_lowercase().replace('_', "");
+    (key == "name" && !path.contains('.'))
+        || ["firstname", "middlename", "lastname", "fullname"].contains(&key.as_str())
+}
+
+/// `Rahul Kumar Sharma` becomes `R. K. S.`
+fn initials(name: &str) -> String {
+    name.split_whitespace()
+        .filter_map(|word| word.chars().next())
+        .map(|c| format!("{}.", c.to_uppercase()))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// First 16 hex digits of SHA-256 over the salt and the value.
+fn hash(salt: &str, value: &str) -> String {
+    let digest = Sha256::new()
+        .chain_update(salt.as_bytes())
+        .chain_update(b":")
+        .chain_update(value.as_bytes())
+        .finalize();
+    digest.iter().take(8).map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mongodb::bson::doc;
+
+    fn redact(doc: &Document, hashed: &[&str]) -> Document {
+        let redaction = Redaction {
+            enabled: true,
+            hashed: hashed.iter().map(|field| field.to_string()).collect(),
+            salt: "salt".into(),
+        };
+        redact_document(&redaction, doc, "")
+    }
+
+    #[test]
+    fn mask_keeps_the_first_and_last_two_digits() {
+        assert_eq!(mask("9876543210"), "98******10");
+        assert_eq!(mask("98765"), "98*65");
+    }
+
+    #[test]
+    fn mask_hides_short_phones_entirely() {
+        assert_eq!(mask(""), "");
+        assert_eq!(mask("123"), "***");
+        assert_eq!(mask("1234"), "****");
+    }
+
+    #[test]
+    fn numeric_phones_are_masked() {
+        let doc = redact(&doc! { "phone": 9876543210_i64, "mobile": 98765_i32 }, &[]);
+        assert_eq!(doc.get_str("phone").unwrap(), "98******10");
+        assert_eq!(doc.get_str("mobile").unwrap(), "98*65");
+    }
+
+    #[test]
+    fn only_phone_fields_are_masked() {
+        let doc = redact(
+            &doc! {
+                "alt_phone": "9876543210",
+                "alternateMobile": "9876543210",
+                "phoneNumber": "9876543210",
+                "phoneCount": 12,
+                "duplicatePhones": 3_i64,
+                "phones": "several",
+            },
+            &[],
+        );
+        assert_eq!(doc.get_str("alt_phone").unwrap(), "98******10");
+        assert_eq!(doc.get_str("alternateMobile").unwrap(), "98******10");
+        assert_eq!(doc.get_str("phoneNumber").unwrap(), "98******10");
+        assert_eq!(doc.get_i32("phoneCount").unwrap(), 12);
+        assert_eq!(doc.get_i64("duplicatePhones").unwrap(), 3);
+        assert_eq!(doc.get_str("phones").unwrap(), "several");
+    }
+
+    #[test]
+    fn group_ids_that_are_phones_are_masked() {
+        let grouped = redact(&doc! { "_id": "+919876543210", "count": 2 }, &[]);
+        assert_eq!(grouped.get_str("_id").unwrap(), "+9*********10");
+        let partner = redact(&doc! { "_id": "partner-1", "count": 2 }, &[]);
+        assert_eq!(partner.get_str("_id").unwrap(), "partner-1");
+    }
+
+    #[test]
+    fn names_become_initials_except_nested_name() {
+        let doc = redact(
+            &doc! {
+                "name": "rahul kumar sharma",
+                "firstName": "Rahul",
+                "last_name": "Sharma",
+                "accounts": [{ "name": "Mpocket" }],
+            },
+            &[],
+        );
+        assert_eq!(doc.get_str("name").unwrap(), "R. K. S.");
+        assert_eq!(doc.get_str("firstName").unwrap(), "R.");
+        assert_eq!(doc.get_str("last_name").unwrap(), "S.");
+        let account = doc.get_array("accounts").unwrap()[0].as_document().unwrap();
+        assert_eq!(account.get_str("name").unwrap(), "Mpocket");
+    }
+
+    #[test]
+    fn hashed_paths_match_nested_fields() {
+        let doc = redact(
+            &doc! { "pan": "ABCDE1234F", "address": { "pincode": "560001", "city": "Bengaluru" } },
+            &["address.pincode"],
+        );
+        assert_eq!(doc.get_str("pan").unwrap(), "ABCDE1234F");
+        let address = doc.get_document("address").unwrap();
+        assert_eq!(address.get_str("pincode").unwrap(), hash("salt", "560001"));
+        assert_eq!(address.get_str("city").unwrap(), "Bengaluru");
+    }
+}
@@ -0,0 +1,51 @@
+//! Colorized rendering of BSON values for the terminal, one color and mongosh-style annotation
+//! per type so e.g. an Int64 is told apart from an Int32 and a date from a string.
+
+use colored::*;
+use mongodb::bson::{Bson, Document};
+
+pub fn colorize_document(doc: &Document, indent: usize) -> String {
+    if doc.is_empty() {
+        return "{}".to_string();
+    }
+    let contents: Vec<String> = doc
+        .iter()
+        .map(|(k, v)| format!("{}{}: {}", "  ".repeat(indent + 1), k.green(), colorize(v, indent + 1)))
+        .collect();
+    format!("{{\n{}\n{}}}", contents.join(",\n"), "  ".repeat(indent))
+}
+
+pub fn colorize(value: &Bson, indent: usize) -> String {
+    match value {
+        Bson::Document(doc) => colorize_document(doc, indent),
+        Bson::Array(items) => {
+            let contents: Vec<String> = items.iter().map(|v| colorize(v, indent + 1)).collect();
+            format!("[{}]", contents.join(", "))
+        }
+        Bson::String(s) => format!("'{}'", s.cyan()),
+        Bson::DateTime(date) => {
+            let date = date.try_to_rfc3339_string().unwrap_or_else(|_| date.to_string());
+            format!("ISODate('{}')", date).purple().to_string()
+        }
+        Bson::ObjectId(id) => format!("ObjectId('{}')", id.to_hex()).blue().to_string(),
+        Bson::Int32(n) => n.to_string().yellow().to_string(),
+        Bson::Int64(n) => format!("Long({})", n).yellow().to_string(),
+        Bson::Double(n) => format!("{:?}", n).bright_yellow().to_string(),
+        Bson::Decimal128(d) => format!("Decimal128('{}')", d).bright_yellow().to_string(),
+        Bson::Boolean(b) => b.to_string().magenta().to_string(),
+        Bson::Null => "null".bright_black().to_string(),
+        Bson::Undefined => "undefined".bright_black().to_string(),
+        Bson::Binary(binary) => {
+            let json = Bson::Binary(binary.clone()).into_canonical_extjson();
+            let base64 = json["$binary"]["base64"].as_str().unwrap_or_default().to_string();
+            format!("Binary('{}', {})", base64, u8::from(binary.subtype))
+                .bright_red()
+                .to_string()
+        }
+        Bson::Timestamp(ts) => format!("Timestamp({}, {})", ts.time, ts.increment)
+            .bright_blue()
+            .to_string(),
+        Bson::RegularExpression(regex) => format!("/{}/{}", regex.pattern, regex.options).red().to_string(),
+        other => other.to_string().bright_black().to_string(),
+    }
+}
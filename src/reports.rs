@@ -0,0 +1,224 @@
+use crate::error::Result;
+use crate::export;
+use crate::output;
+use crate::query;
+use crate::redact;
+use colored::*;
+use futures_util::stream::TryStreamExt;
+use mongodb::bson::{doc, Bson, DateTime, Document};
+use mongodb::Collection;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// A saved aggregation loaded from `<reports dir>/<name>.json`.
+///
+/// Any string in `pipeline` of the form `{{param}}` is replaced by the typed parameter value;
+/// `{{param}}` inside a longer string is substituted as text.
+#[derive(Debug, Deserialize)]
+pub struct ReportDefinition {
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub params: BTreeMap<String, ParamSpec>,
+    pub pipeline: Vec<serde_json::Value>,
+    #[serde(default)]
+    pub columns: Vec<Column>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ParamSpec {
+    #[serde(rename = "type", default)]
+    pub kind: ParamType,
+    /// Used when the parameter is not given; parameters without a default are required.
+    pub default: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ParamType {
+    #[default]
+    String,
+    Date,
+    Int,
+    Number,
+    Bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Column {
+    /// Dotted path into each result document.
+    pub field: String,
+    /// Column title, defaults to `field`.
+    pub header: Option<String>,
+}
+
+impl ParamType {
+    fn parse(self, name: &str, value: &str) -> Result<Bson> {
+        let invalid = |e: &dyn std::fmt::Display| format!("parameter {:?}: {:?} {}", name, value, e);
+        Ok(match self {
+            ParamType::String => Bson::String(value.to_string()),
+            ParamType::Date => Bson::DateTime(DateTime::parse_rfc3339_str(value).map_err(|e| invalid(&e))?),
+            ParamType::Int => Bson::Int64(value.parse().map_err(|e| invalid(&e))?),
+            ParamType::Number => Bson::Double(value.parse().map_err(|e| invalid(&e))?),
+            ParamType::Bool => Bson::Boolean(value.parse().map_err(|e| invalid(&e))?),
+        })
+    }
+}
+
+/// Prints every report in `dir` with its description and parameters.
+pub fn list(dir: &str) -> Result<()> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .collect();
+    paths.sort();
+
+    let mut records = Vec::new();
+    for path in paths {
+        let name = path.file_stem().unwrap_or_default().to_string_lossy().to_string();
+        records.push(match load(&path) {
+            Ok(report) => {
+                let params: Document = report
+                    .params
+                    .iter()
+                    .map(|(name, spec)| (name.clone(), spec.default.clone().map_or(Bson::Null, Bson::String)))
+                    .collect();
+                doc! { "name": name, "description": report.description, "params": params }
+            }
+            Err(e) => doc! { "name": name, "error": e.to_string() },
+        });
+    }
+
+    output::emit(&records, || {
+        for record in &records {
+            let name = record.get_str("name").unwrap_or_default();
+            if let Ok(error) = record.get_str("error") {
+                println!("{:<28} {}", name.red(), error);
+                continue;
+            }
+            println!(
+                "{:<28} {}",
+                name.green(),
+                record.get_str("description").unwrap_or_default()
+            );
+            let params: Vec<String> = record
+                .get_document("params")
+                .map(|params| {
+                    params
+                        .iter()
+                        .map(|(name, default)| match default {
+                            Bson::String(default) => format!("{}={}", name, default),
+                            _ => format!("{} (required)", name),
+                        })
+                        .collect()
+                })
+                .unwrap_or_default();
+            if !params.is_empty() {
+                println!("{:<28} {}", "", params.join(", ").bright_black());
+            }
+        }
+    })
+}
+
+/// Runs the saved report `name` from `dir` with `key=value` parameters and prints a table.
+pub async fn run(collection: &Collection<Document>, dir: &str, name: &str, params: &[String]) -> Result<()> {
+    let report = load(&Path::new(dir).join(format!("{}.json", name)))?;
+
+    let mut given: HashMap<&str, &str> = HashMap::new();
+    for param in params {
+        let (key, value) = param
+            .split_once('=')
+            .ok_or_else(|| format!("--param expects key=value, got {:?}", param))?;
+        if !report.params.contains_key(key) {
+            return Err(format!("report {:?} has no parameter {:?}", name, key).into());
+        }
+        given.insert(key, value);
+    }
+    let mut values: HashMap<&str, Bson> = HashMap::new();
+    for (key, spec) in &report.params {
+        let value = given
+            .get(key.as_str())
+            .copied()
+            .or(spec.default.as_deref())
+            .ok_or_else(|| format!("report {:?} requires --param {}=...", name, key))?;
+        values.insert(key, spec.kind.parse(key, value)?);
+    }
+
+    let mut pipeline = Vec::new();
+    for stage in &report.pipeline {
+        match substitute(Bson::try_from(stage.clone())?, &values) {
+            Bson::Document(stage) => pipeline.push(stage),
+            other => return Err(format!("pipeline stage is not a document: {}", other).into()),
+        }
+    }
+    query::read_only(&pipeline).map_err(|e| format!("report {:?}: {}", name, e))?;
+
+    let documents: Vec<Document> = query::aggregate(collection, pipeline, None)
+        .await?
+        .try_collect()
+        .await?;
+    let columns: Vec<(String, String)> = if report.columns.is_empty() {
+        documents
+            .first()
+            .map(|doc| doc.keys().map(|k| (k.clone(), k.clone())).collect())
+            .unwrap_or_default()
+    } else {
+        report
+            .columns
+            .iter()
+            .map(|c| (c.field.clone(), c.header.clone().unwrap_or_else(|| c.field.clone())))
+            .collect()
+    };
+
+    let records: Vec<Document> = documents
+        .iter()
+        .map(|doc| {
+            let doc = redact::document(doc);
+            columns
+                .iter()
+                .map(|(field, header)| {
+                    (
+                        header.clone(),
+                        export::lookup(&doc, field).cloned().unwrap_or(Bson::Null),
+                    )
+                })
+                .collect()
+        })
+        .collect();
+    output::emit(&records, || output::table(&records))
+}
+
+fn load(path: &Path) -> Result<ReportDefinition> {
+    let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    Ok(serde_json::from_str(&text).map_err(|e| format!("{}: {}", path.display(), e))?)
+}
+
+/// Replaces `{{param}}` placeholders throughout a pipeline value.
+fn substitute(value: Bson, params: &HashMap<&str, Bson>) -> Bson {
+    match value {
+        Bson::String(s) => {
+            let whole = s.strip_prefix("{{").and_then(|rest| rest.strip_suffix("}}"));
+            if let Some(param) = whole.and_then(|name| params.get(name.trim())) {
+                return param.clone();
+            }
+            let mut text = s;
+            for (name, param) in params {
+                let placeholder = format!("{{{{{}}}}}", name);
+                if text.contains(&placeholder) {
+                    let replacement = match param {
+                        Bson::String(s) => s.clone(),
+                        Bson::DateTime(d) => d.try_to_rfc3339_string().unwrap_or_else(|_| d.to_string()),
+                        other => other.to_string(),
+                    };
+                    text = text.replace(&placeholder, &replacement);
+                }
+            }
+            Bson::String(text)
+        }
+        Bson::Array(items) => Bson::Array(items.into_iter().map(|v| substitute(v, params)).collect()),
+        Bson::Document(doc) => Bson::Document(doc.into_iter().map(|(k, v)| (k, substitute(v, params))).collect()),
+        other => other,
+    }
+}
@@ -0,0 +1,90 @@
+//! Confirmation for commands that modify or delete users: the target and the number of documents
+//! affected are shown first, and the user has to type the namespace back before anything is written.
+
+use crate::analytics::as_f64;
+use crate::config::Target;
+use crate::error::{Error, Result};
+use crate::query;
+use colored::*;
+use futures_util::stream::TryStreamExt;
+use mongodb::bson::{doc, Document};
+use mongodb::options::AggregateOptions;
+use mongodb::Collection;
+use std::io::{self, BufRead, IsTerminal, Write};
+
+/// What a destructive command is about to do.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Plan {
+    pub modified: u64,
+    pub deleted: u64,
+}
+
+/// The plan of a merge over the phones `duplicates` yields: one user kept per phone and every
+/// other user with that phone deleted, whether or not it falls in the window `duplicates` matched.
+pub async fn merge_plan(collection: &Collection<Document>, mut duplicates: Vec<Document>) -> Result<Plan> {
+    duplicates.extend([
+        // The merge looks phones up as strings and skips the rest.
+        doc! { "$match": { "phone": { "$type": "string" } } },
+        doc! { "$lookup": { "from": collection.name(), "localField": "phone", "foreignField": "phone", "as": "users" } },
+        doc! { "$group": { "_id": null, "phones": { "$sum": 1 }, "users": { "$sum": { "$size": "$users" } } } },
+    ]);
+    let totals = query::aggregate(collection, duplicates, AggregateOptions::default())
+        .await?
+        .try_next()
+        .await?
+        .unwrap_or_default();
+    let phones = as_f64(totals.get("phones")) as u64;
+    let users = as_f64(totals.get("users")) as u64;
+    Ok(Plan {
+        modified: phones,
+        deleted: users.saturating_sub(phones),
+    })
+}
+
+/// Fails when `target` is a protected profile and `allow_protected` was not given.
+pub fn protect(command: &str, target: &Target, allow_protected: bool) -> Result<()> {
+    if target.protected && !allow_protected {
+        return Err(Error::Validation(format!(
+            "profile {} is protected; rerun with --allow-protected to let {} write to it",
+            target.profile.as_deref().unwrap_or_default(),
+            command
+        )));
+    }
+    Ok(())
+}
+
+/// Shows `plan` against `target` and fails unless the user confirms it; `yes` skips the prompt.
+pub fn confirm(command: &str, target: &Target, plan: Plan, yes: bool) -> Result<()> {
+    let namespace = format!("{}.{}", target.database, target.collection);
+    eprintln!(
+        "{} will modify {} and delete {} users",
+        command.bold(),
+        plan.modified.to_string().yellow(),
+        plan.deleted.to_string().red()
+    );
+    eprintln!("  {:<11} {}", "host", target.host());
+    eprintln!("  {:<11} {}", "database", target.database);
+    eprintln!("  {:<11} {}", "collection", target.collection);
+    if let Some(profile) = &target.profile {
+        let protected = if target.protected { " (protected)" } else { "" };
+        eprintln!("  {:<11} {}{}", "profile", profile, protected.red());
+    }
+    if plan.modified == 0 && plan.deleted == 0 || yes {
+        return Ok(());
+    }
+
+    if !io::stdin().is_terminal() {
+        return Err(Error::Validation(format!(
+            "stdin is not a terminal; pass --yes to run {} without a prompt",
+            command
+        )));
+    }
+    eprint!("Type {} to continue: ", namespace.bold());
+    io::stderr().flush().ok();
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+    if answer.trim() != namespace {
+        return Err(Error::Aborted);
+    }
+    Ok(())
+}
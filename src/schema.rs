@@ -0,0 +1,174 @@
+use crate::error::Result;
+use crate::output;
+use crate::query;
+use colored::*;
+use futures_util::stream::TryStreamExt;
+use mongodb::bson::{doc, Bson, Document};
+use mongodb::Collection;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Field statistics for one group of documents: the users themselves, or one lender's accounts.
+#[derive(Default)]
+struct Group {
+    documents: usize,
+    /// path -> documents containing it at least once
+    present: BTreeMap<String, usize>,
+    /// path -> BSON type name -> occurrences
+    types: BTreeMap<String, BTreeMap<&'static str, usize>>,
+}
+
+impl Group {
+    fn add(&mut self, doc: &Document, skip: Option<&str>) {
+        self.documents += 1;
+        let mut seen = BTreeSet::new();
+        self.walk_document(doc, "", skip, &mut seen);
+        for path in seen {
+            *self.present.entry(path).or_default() += 1;
+        }
+    }
+
+    fn walk_document(&mut self, doc: &Document, prefix: &str, skip: Option<&str>, seen: &mut BTreeSet<String>) {
+        for (key, value) in doc {
+            let path = if prefix.is_empty() {
+                key.clone()
+            } else {
+                format!("{}.{}", prefix, key)
+            };
+            self.walk_value(value, path, skip, seen);
+        }
+    }
+
+    fn walk_value(&mut self, value: &Bson, path: String, skip: Option<&str>, seen: &mut BTreeSet<String>) {
+        *self
+            .types
+            .entry(path.clone())
+            .or_default()
+            .entry(type_name(value))
+            .or_default() += 1;
+        seen.insert(path.clone());
+        if Some(path.as_str()) == skip {
+            return;
+        }
+        match value {
+            Bson::Document(child) => self.walk_document(child, &path, skip, seen),
+            Bson::Array(items) => {
+                let path = format!("{}[]", path);
+                for item in items {
+                    self.walk_value(item, path.clone(), skip, seen);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn present(&self, path: &str) -> f64 {
+        self.present.get(path).copied().unwrap_or(0) as f64 * 100.0 / self.documents as f64
+    }
+
+    fn records(&self, group: &str) -> Vec<Document> {
+        self.types
+            .iter()
+            .map(|(path, types)| {
+                let types: Document = types
+                    .iter()
+                    .map(|(name, count)| (name.to_string(), Bson::Int64(*count as i64)))
+                    .collect();
+                doc! {
+                    "group": group,
+                    "documents": self.documents as i64,
+                    "path": path,
+                    "present_percent": self.present(path),
+                    "types": types,
+                }
+            })
+            .collect()
+    }
+
+    fn print(&self, title: &str) {
+        println!("\n{} ({} documents)", title.bold(), self.documents);
+        println!("  {:<56} {:>8}  types", "path", "present");
+        for (path, types) in &self.types {
+            let occurrences: usize = types.values().sum();
+            let mut types: Vec<(&&str, &usize)> = types.iter().collect();
+            types.sort_by(|a, b| b.1.cmp(a.1));
+            let types: Vec<String> = types
+                .iter()
+                .map(|(name, count)| format!("{} {:.0}%", name, **count as f64 * 100.0 / occurrences as f64))
+                .collect();
+            let present = format!("{:>7.1}%", self.present(path));
+            println!(
+                "  {:<56} {}  {}",
+                path.green(),
+                if present.trim() == "100.0%" {
+                    present.normal()
+                } else {
+                    present.yellow()
+                },
+                types.join(", ")
+            );
+        }
+    }
+}
+
+/// Samples `size` users and reports every field path with its BSON types and how often it is
+/// present, then does the same for each lender's subdocuments in `accounts`, grouped by `name`.
+pub async fn schema(collection: &Collection<Document>, size: i64) -> Result<()> {
+    let mut cursor = query::aggregate(collection, vec![doc! { "$sample": { "size": size } }], None).await?;
+
+    let mut users = Group::default();
+    let mut lenders: BTreeMap<String, Group> = BTreeMap::new();
+    while let Some(doc) = cursor.try_next().await? {
+        users.add(&doc, Some("accounts[]"));
+        if let Ok(accounts) = doc.get_array("accounts") {
+            for account in accounts.iter().filter_map(Bson::as_document) {
+                let name = account.get_str("name").unwrap_or("(no name)").to_string();
+                lenders.entry(name).or_default().add(account, None);
+            }
+        }
+    }
+
+    let mut records = users.records("users");
+    for (name, group) in &lenders {
+        records.extend(group.records(&format!("accounts: {}", name)));
+    }
+
+    output::emit(&records, || {
+        println!(
+            "Schema of {} from a sample of {}",
+            collection.name().cyan(),
+            users.documents
+        );
+        if users.documents == 0 {
+            return;
+        }
+        users.print("users");
+        for (name, group) in &lenders {
+            group.print(&format!("accounts: {}", name));
+        }
+    })
+}
+
+fn type_name(value: &Bson) -> &'static str {
+    match value {
+        Bson::Double(_) => "double",
+        Bson::String(_) => "string",
+        Bson::Array(_) => "array",
+        Bson::Document(_) => "object",
+        Bson::Boolean(_) => "bool",
+        Bson::Null => "null",
+        Bson::RegularExpression(_) => "regex",
+        Bson::JavaScriptCode(_) | Bson::JavaScriptCodeWithScope(_) => "javascript",
+        Bson::Int32(_) => "int",
+        Bson::Int64(_) => "long",
+        Bson::Timestamp(_) => "timestamp",
+        Bson::Binary(_) => "binData",
+        Bson::ObjectId(_) => "objectId",
+        Bson::DateTime(_) => "date",
+        Bson::Symbol(_) => "symbol",
+        Bson::Decimal128(_) => "decimal",
+        Bson::Undefined => "undefined",
+        Bson::MaxKey => "maxKey",
+        Bson::MinKey => "minKey",
+        Bson::DbPointer(_) => "dbPointer",
+    }
+}
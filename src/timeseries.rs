@@ -0,0 +1,181 @@
+use crate::chart;
+use crate::error::Result;
+use crate::output;
+use crate::query;
+use chrono::{DateTime as ChronoDateTime, Months, Utc};
+use clap::ValueEnum;
+use colored::*;
+use futures_util::stream::TryStreamExt;
+use mongodb::bson::{doc, Bson, DateTime, Document};
+use mongodb::Collection;
+use std::collections::BTreeMap;
+
+const BAR_WIDTH: usize = 40;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Unit {
+    Day,
+    Week,
+    Month,
+}
+
+impl Unit {
+    fn as_str(self) -> &'static str {
+        match self {
+            Unit::Day => "day",
+            Unit::Week => "week",
+            Unit::Month => "month",
+        }
+    }
+
+    /// Start of the bucket after `bucket`, used to fill in buckets without any users.
+    fn next(self, bucket: ChronoDateTime<Utc>) -> ChronoDateTime<Utc> {
+        match self {
+            Unit::Day => bucket + chrono::Duration::days(1),
+            Unit::Week => bucket + chrono::Duration::weeks(1),
+            Unit::Month => bucket.checked_add_months(Months::new(1)).unwrap_or(bucket),
+        }
+    }
+
+    fn label(self, bucket: ChronoDateTime<Utc>) -> String {
+        match self {
+            Unit::Day | Unit::Week => bucket.format("%Y-%m-%d").to_string(),
+            Unit::Month => bucket.format("%Y-%m").to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Chart {
+    Bar,
+    Sparkline,
+}
+
+/// Counts users per `unit` of `field` (`createdAt` or `updatedAt`) inside the window, optionally
+/// split by partner, and prints a table with an inline chart.
+pub async fn timeseries(
+    collection: &Collection<Document>,
+    field: &str,
+    unit: Unit,
+    by_partner: bool,
+    chart: Chart,
+    from: DateTime,
+    to: DateTime,
+) -> Result<()> {
+    let mut group_id = doc! { "bucket": { "$dateTrunc": { "date": format!("${}", field), "unit": unit.as_str() } } };
+    if by_partner {
+        group_id.insert("partner", "$partner");
+    }
+    let pipeline = vec![
+        doc! { "$match": { field: { "$gte": from, "$lt": to } } },
+        doc! { "$group": { "_id": group_id, "count": { "$sum": 1 } } },
+        doc! { "$sort": { "_id.bucket": 1 } },
+    ];
+    let mut cursor = query::aggregate(collection, pipeline, None).await?;
+
+    // series name -> bucket start (millis) -> count
+    let mut series: BTreeMap<String, BTreeMap<i64, f64>> = BTreeMap::new();
+    while let Some(doc) = cursor.try_next().await? {
+        let id = doc.get_document("_id")?;
+        let bucket = id.get_datetime("bucket")?.timestamp_millis();
+        let name = match id.get("partner") {
+            Some(Bson::String(partner)) => partner.clone(),
+            Some(Bson::Null) | None if by_partner => "(none)".to_string(),
+            Some(other) => other.to_string(),
+            None => "users".to_string(),
+        };
+        let count = match doc.get("count") {
+            Some(Bson::Int32(n)) => *n as f64,
+            Some(Bson::Int64(n)) => *n as f64,
+            _ => 0.0,
+        };
+        *series.entry(name).or_default().entry(bucket).or_default() += count;
+    }
+
+    let mut buckets = Vec::new();
+    if let (Some(first), Some(last)) = (
+        series.values().filter_map(|s| s.keys().next()).min(),
+        series.values().filter_map(|s| s.keys().next_back()).max(),
+    ) {
+        let mut bucket = ChronoDateTime::from_timestamp_millis(*first).ok_or("bucket out of range")?;
+        while bucket.timestamp_millis() <= *last {
+            buckets.push(bucket);
+            bucket = unit.next(bucket);
+        }
+    }
+
+    let mut names: Vec<&String> = series.keys().collect();
+    names.sort_by(|a, b| total(&series[*b]).total_cmp(&total(&series[*a])));
+    let count = |name: &String, bucket: &ChronoDateTime<Utc>| {
+        series[name].get(&bucket.timestamp_millis()).copied().unwrap_or(0.0)
+    };
+    let totals: Vec<f64> = buckets
+        .iter()
+        .map(|b| names.iter().map(|name| count(name, b)).sum())
+        .collect();
+    let max = totals.iter().copied().fold(0.0, f64::max);
+
+    let mut records = Vec::new();
+    for (bucket, total) in buckets.iter().zip(&totals) {
+        let mut record = doc! { "bucket": unit.label(*bucket) };
+        if by_partner {
+            for name in &names {
+                record.insert(name.as_str(), count(name, bucket));
+            }
+        }
+        record.insert("total", total);
+        records.push(record);
+    }
+
+    output::emit(&records, || {
+        println!(
+            "Users by {} per {} ({} to {})",
+            field.cyan(),
+            unit.as_str().cyan(),
+            from,
+            to
+        );
+        if buckets.is_empty() {
+            println!("No users in this window");
+            return;
+        }
+
+        let mut header = format!("{:<12}", "bucket");
+        if by_partner {
+            for name in &names {
+                header.push_str(&format!(" {:>12}", chart::truncate(name, 12)));
+            }
+        }
+        header.push_str(&format!(" {:>12}", "total"));
+        println!("\n{}", header.bold());
+
+        for (bucket, total) in buckets.iter().zip(&totals) {
+            let mut line = format!("{:<12}", unit.label(*bucket));
+            if by_partner {
+                for name in &names {
+                    line.push_str(&format!(" {:>12}", count(name, bucket)));
+                }
+            }
+            line.push_str(&format!(" {:>12}", total.to_string().yellow()));
+            if chart == Chart::Bar {
+                line.push_str(&format!("  {}", chart::bar(*total, max, BAR_WIDTH)));
+            }
+            println!("{}", line);
+        }
+
+        if chart == Chart::Sparkline {
+            println!();
+            if by_partner {
+                for name in &names {
+                    let values: Vec<f64> = buckets.iter().map(|b| count(name, b)).collect();
+                    println!("{:<20} {}", chart::truncate(name, 20), chart::sparkline(&values));
+                }
+            }
+            println!("{:<20} {}", "total", chart::sparkline(&totals));
+        }
+    })
+}
+
+fn total(series: &BTreeMap<i64, f64>) -> f64 {
+    series.values().sum()
+}